@@ -1,10 +1,13 @@
 use core::result::Result;
 use core::result::Result::Ok;
 use std::fmt::Debug;
+use std::io;
+use std::io::BufRead;
 
 use aws_sdk_s3::client::Client;
 use aws_sdk_s3::Error;
 use aws_sdk_s3::error::{CreateBucketError, DeleteBucketError};
+use aws_sdk_s3::Error::Unhandled;
 use aws_sdk_s3::model::{BucketLocationConstraint, CreateBucketConfiguration};
 use aws_sdk_s3::output::{CreateBucketOutput, DeleteBucketOutput};
 use aws_sdk_s3::Region;
@@ -12,6 +15,9 @@ use aws_smithy_http::result::SdkError;
 
 use crate::{ClientBucket, OutputPrinter};
 use crate::date_utils::convert_date_time;
+use crate::file_delete::delete_versions_in_batches;
+use crate::object_copy::copy_object_any_size;
+use crate::object_versions::VersionPages;
 
 pub async fn list_buckets(client: &Client,
                           output_printer: &dyn OutputPrinter,
@@ -84,6 +90,112 @@ pub async fn delete_bucket(client_bucket: &ClientBucket,
     res
 }
 
+/// Empties the bucket before deleting it: all object versions, delete markers and unfinished
+/// multipart uploads are removed once the bucket name has been typed in as confirmation.
+/// They are counted first and then deleted page by page.
+pub async fn force_delete_bucket(client_bucket: &ClientBucket,
+                                 output_printer: &dyn OutputPrinter) -> Result<(), Error> {
+    let bucket_name = &client_bucket.bucket_name;
+    let mut total = 0;
+    let mut version_pages = VersionPages::all(client_bucket);
+    while let Some(page) = version_pages.next_page().await? {
+        total += page.len();
+    }
+    let mut uploads = 0;
+    let mut upload_pages = MultipartUploadPages::new(client_bucket);
+    while let Some(page) = upload_pages.next_page().await? {
+        uploads += page.len();
+    }
+
+    let confirmed = confirm_deletion(bucket_name, total, uploads, output_printer, &mut io::stdin().lock())
+        .map_err(|e| Unhandled(e.into()))?;
+    if !confirmed {
+        output_printer.err_output(format!("Bucket {} has not been deleted.", bucket_name).as_str());
+        return Ok(());
+    }
+
+    let mut upload_pages = MultipartUploadPages::new(client_bucket);
+    while let Some(page) = upload_pages.next_page().await? {
+        for (key, upload_id) in page {
+            let res = client_bucket.client
+                .abort_multipart_upload()
+                .bucket(bucket_name)
+                .key(&key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+            if let Err(e) = res {
+                output_printer.err_output(format!("Could not abort the upload of {}: {:?}", key, e).as_str());
+            }
+        }
+    }
+    let mut deleted = 0;
+    let mut version_pages = VersionPages::all(client_bucket);
+    while let Some(page) = version_pages.next_page().await? {
+        let versions = page.into_iter()
+            .map(|version| (version.key, Some(version.version_id)))
+            .collect();
+        deleted += delete_versions_in_batches(client_bucket, versions, false, output_printer).await.len();
+        output_printer.info_output(format!("Deleted {} of {} object versions and delete markers", deleted, total).as_str());
+    }
+    // Reports its own outcome
+    let _ = delete_bucket(client_bucket, output_printer).await;
+    Ok(())
+}
+
+/// Shows what is about to be deleted and reads the confirmation, which is the bucket name.
+fn confirm_deletion(bucket_name: &str,
+                    versions: usize,
+                    uploads: usize,
+                    output_printer: &dyn OutputPrinter,
+                    input: &mut impl BufRead) -> io::Result<bool> {
+    output_printer.info_output(format!("Bucket {} holds {} object versions and delete markers and {} unfinished multipart uploads.",
+                                       bucket_name, versions, uploads).as_str());
+    output_printer.info_output("Type the bucket name to delete all of them together with the bucket:");
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(answer.trim() == bucket_name)
+}
+
+/// Pages through `list_multipart_uploads`, handing out the key and upload id of each unfinished upload.
+struct MultipartUploadPages<'a> {
+    client_bucket: &'a ClientBucket,
+    key_marker: Option<String>,
+    upload_id_marker: Option<String>,
+    done: bool,
+}
+
+impl<'a> MultipartUploadPages<'a> {
+    fn new(client_bucket: &'a ClientBucket) -> MultipartUploadPages<'a> {
+        MultipartUploadPages {
+            client_bucket,
+            key_marker: None,
+            upload_id_marker: None,
+            done: false,
+        }
+    }
+
+    async fn next_page(&mut self) -> Result<Option<Vec<(String, String)>>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        let output = self.client_bucket.client
+            .list_multipart_uploads()
+            .bucket(&self.client_bucket.bucket_name)
+            .set_key_marker(self.key_marker.take())
+            .set_upload_id_marker(self.upload_id_marker.take())
+            .send()
+            .await?;
+        self.key_marker = output.next_key_marker().map(|m| m.to_string());
+        self.upload_id_marker = output.next_upload_id_marker().map(|m| m.to_string());
+        self.done = !output.is_truncated() || self.key_marker.is_none();
+        Ok(Some(output.uploads().unwrap_or_default().iter()
+            .map(|upload| (upload.key().unwrap_or_default().to_string(),
+                           upload.upload_id().unwrap_or_default().to_string()))
+            .collect()))
+    }
+}
+
 pub async fn copy_to_bucket(client_bucket: &ClientBucket,
                             output_printer: &dyn OutputPrinter) {
     let source_bucket = &client_bucket.args.bucket.as_ref()
@@ -94,18 +206,12 @@ pub async fn copy_to_bucket(client_bucket: &ClientBucket,
         .expect("Source key is missing. Please specify the source key.");
     let target_key = &client_bucket.args.target_key.as_ref()
         .expect("Target key is missing. Please specify the target key.");
-    let client = &client_bucket.client;
     let mut source_bucket_and_object = "".to_string();
     source_bucket_and_object += source_bucket;
     source_bucket_and_object += "/";
     source_bucket_and_object += source_key;
-    let res = client
-        .copy_object()
-        .copy_source(source_bucket_and_object.clone())
-        .bucket(*target_bucket)
-        .key(*target_key)
-        .send()
-        .await;
+    let res = copy_object_any_size(client_bucket, source_bucket, source_key,
+                                   client_bucket.args.version_id.as_deref(), target_bucket, target_key).await;
     match res {
         Ok(_) => {
             output_printer.ok_output(
@@ -135,3 +241,41 @@ pub fn print_message<O, E>(res: &Result<O, SdkError<E>>,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    use aws_sdk_s3::model::Object;
+
+    use crate::object_versions::VersionRecord;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingPrinter {
+        info: RefCell<Vec<String>>,
+    }
+
+    impl OutputPrinter for RecordingPrinter {
+        fn output_with_stats(&self, _: &Object) {}
+        fn output_version(&self, _: &VersionRecord) {}
+        fn err_output(&self, _: &str) {}
+        fn ok_output(&self, _: &str) {}
+        fn info_output(&self, msg: &str) {
+            self.info.borrow_mut().push(msg.to_string());
+        }
+    }
+
+    #[test]
+    fn test_confirm_deletion() {
+        let printer = RecordingPrinter::default();
+        assert!(confirm_deletion("mdm-eu-dev", 2500, 3, &printer, &mut Cursor::new("mdm-eu-dev\n")).unwrap());
+        assert_eq!(printer.info.borrow()[0],
+                   "Bucket mdm-eu-dev holds 2500 object versions and delete markers and 3 unfinished multipart uploads.");
+        assert!(!confirm_deletion("mdm-eu-dev", 2500, 3, &printer, &mut Cursor::new("yes\n")).unwrap());
+        assert!(!confirm_deletion("mdm-eu-dev", 2500, 3, &printer, &mut Cursor::new("")).unwrap());
+    }
+}
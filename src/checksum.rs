@@ -0,0 +1,172 @@
+use std::io;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use aws_sdk_s3::model::ServerSideEncryption;
+use md5::{Digest, Md5};
+use md5::digest::Output;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Base64 encoded MD5 digest, as expected by the `Content-MD5` header.
+pub(crate) fn md5_base64(digest: &Output<Md5>) -> String {
+    base64::encode(digest)
+}
+
+pub(crate) fn md5_hex(digest: &Output<Md5>) -> String {
+    format!("{:x}", digest)
+}
+
+/// MD5 digest of the `length` bytes of a file from `offset` on, read piece by piece.
+pub(crate) async fn file_md5(path: &Path, offset: u64, length: u64) -> io::Result<Output<Md5>> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut reader = file.take(length);
+    let mut hasher = Md5::new();
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let mut remaining = length;
+    while remaining > 0 {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{:?} ended {} bytes early", path, remaining)));
+        }
+        hasher.update(&buffer[..read]);
+        remaining -= read as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+/// Only the ETags of unencrypted objects and of objects encrypted with SSE-S3 are derived from the
+/// MD5 of the content, not those of objects encrypted with SSE-C, SSE-KMS or DSSE-KMS.
+pub(crate) fn etag_is_md5(server_side_encryption: Option<&ServerSideEncryption>, sse_customer_algorithm: Option<&str>) -> bool {
+    sse_customer_algorithm.is_none() && matches!(server_side_encryption, None | Some(ServerSideEncryption::Aes256))
+}
+
+/// Removes the quotes S3 puts around ETags.
+pub(crate) fn trim_etag(e_tag: &str) -> &str {
+    e_tag.trim_matches('"')
+}
+
+/// Number of parts of a multipart ETag ("md5-of-md5s-N"), `None` for a plain MD5 ETag.
+pub(crate) fn etag_part_count(e_tag: &str) -> Option<u64> {
+    trim_etag(e_tag).split_once('-').and_then(|(_, count)| count.parse().ok())
+}
+
+/// The ETag S3 reports after completing a multipart upload with the given part ETags.
+pub(crate) fn multipart_etag(part_e_tags: &[&str]) -> Option<String> {
+    let mut hasher = Md5::new();
+    for e_tag in part_e_tags {
+        hasher.update(decode_hex(trim_etag(e_tag))?);
+    }
+    Some(format!("{:x}-{}", hasher.finalize(), part_e_tags.len()))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Computes the ETag of data fed to it in arbitrary pieces: the plain MD5 when `part_size` is
+/// `None`, otherwise the multipart form for parts of `part_size` bytes.
+pub(crate) struct EtagHasher {
+    part_size: Option<u64>,
+    current: Md5,
+    current_len: u64,
+    part_digests: Vec<u8>,
+    parts: u64,
+}
+
+impl EtagHasher {
+    pub(crate) fn new(part_size: Option<u64>) -> EtagHasher {
+        EtagHasher {
+            part_size,
+            current: Md5::new(),
+            current_len: 0,
+            part_digests: Vec::new(),
+            parts: 0,
+        }
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        while let Some(part_size) = self.part_size {
+            let remaining = (part_size - self.current_len) as usize;
+            if data.len() < remaining {
+                break;
+            }
+            self.current.update(&data[..remaining]);
+            self.finish_part();
+            data = &data[remaining..];
+        }
+        self.current.update(data);
+        self.current_len += data.len() as u64;
+    }
+
+    pub(crate) fn finish(mut self) -> String {
+        match self.part_size {
+            None => format!("{:x}", self.current.finalize()),
+            Some(_) => {
+                if self.current_len > 0 || self.parts == 0 {
+                    self.finish_part();
+                }
+                format!("{:x}-{}", Md5::digest(&self.part_digests), self.parts)
+            }
+        }
+    }
+
+    fn finish_part(&mut self) {
+        let digest = std::mem::replace(&mut self.current, Md5::new()).finalize();
+        self.part_digests.extend_from_slice(&digest);
+        self.current_len = 0;
+        self.parts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_etag_is_md5() {
+        assert!(etag_is_md5(None, None));
+        assert!(etag_is_md5(Some(&ServerSideEncryption::Aes256), None));
+        assert!(!etag_is_md5(Some(&ServerSideEncryption::AwsKms), None));
+        assert!(!etag_is_md5(Some(&ServerSideEncryption::from("aws:kms:dsse")), None));
+        assert!(!etag_is_md5(Some(&ServerSideEncryption::Aes256), Some("AES256")));
+    }
+
+    #[tokio::test]
+    async fn test_file_md5() {
+        let path = std::env::temp_dir().join("aws_client_test_file_md5.txt");
+        std::fs::write(&path, b"hello world").unwrap();
+        assert_eq!(file_md5(&path, 6, 5).await.unwrap(), Md5::digest(b"world"));
+        assert!(file_md5(&path, 6, 6).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_single_part_etag() {
+        let mut hasher = EtagHasher::new(None);
+        hasher.update(b"hello ");
+        hasher.update(b"world");
+        assert_eq!(hasher.finish(), md5_hex(&Md5::digest(b"hello world")));
+    }
+
+    #[test]
+    fn test_multipart_etag() {
+        let data = b"0123456789";
+        let mut hasher = EtagHasher::new(Some(4));
+        hasher.update(&data[..3]);
+        hasher.update(&data[3..]);
+        let part_e_tags = [md5_hex(&Md5::digest(&data[..4])), md5_hex(&Md5::digest(&data[4..8])), md5_hex(&Md5::digest(&data[8..]))];
+        let part_e_tags: Vec<&str> = part_e_tags.iter().map(|e| e.as_str()).collect();
+        let expected = multipart_etag(&part_e_tags).unwrap();
+        assert_eq!(hasher.finish(), expected);
+        assert_eq!(etag_part_count(format!("\"{}\"", expected).as_str()), Some(3));
+        assert_eq!(etag_part_count(md5_hex(&Md5::digest(data)).as_str()), None);
+    }
+}
@@ -16,7 +16,38 @@ pub enum Operation {
     CreateBucket,
     DeleteBucket,
     CopyBucketToBucket,
-    UploadLarge
+    UploadLarge,
+    Sync,
+    SyncBucketToBucket,
+    RestoreToTime,
+    Undelete,
+    PruneVersions
+}
+
+/// What to do when the destination of a download or upload already exists
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq)]
+#[clap(rename_all = "kebab_case")]
+pub enum ExistsPolicy {
+    Skip,
+    Overwrite,
+    NewerOnly,
+    SizeDiffers
+}
+
+/// Which side of a sync is the source
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq)]
+#[clap(rename_all = "kebab_case")]
+pub enum SyncDirection {
+    Upload,
+    Download
+}
+
+/// Whether a copy takes over the metadata or tags of the source or replaces them
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq)]
+#[clap(rename_all = "kebab_case")]
+pub enum CopyDirective {
+    Copy,
+    Replace
 }
 
 /**
@@ -41,6 +72,14 @@ pub struct Cli {
     #[clap(short, long, value_name = ".+")]
     pub list_regex_pattern: Option<String>,
 
+    /// Key prefix sent to S3 to narrow down listings before the regex filter is applied
+    #[clap(long)]
+    pub prefix: Option<String>,
+
+    /// Delimiter sent to S3 when listing, e.g. '/'. list and list-object-versions then show the common prefixes as directories
+    #[clap(long)]
+    pub delimiter: Option<String>,
+
     /// The bucket in S3
     #[clap(short, long)]
     pub bucket: Option<String>,
@@ -50,7 +89,7 @@ pub struct Cli {
     pub target_bucket: Option<String>,
 
     /// The key prefix in S3 (something like the target folder)
-    /// This is also the target folder for download
+    /// This is also the target folder for download and the key prefix in the target bucket for sync-bucket-to-bucket
     #[clap(short, long)]
     pub target_folder: Option<String>,
 
@@ -63,6 +102,7 @@ pub struct Cli {
     pub sep: Option<String>,
 
     /// Used to sort either in ascending or descending order for all operations that list files on S3.
+    /// Without it objects are processed unsorted, as soon as each page of the listing arrives.
     #[clap(short, long)]
     pub asc: Option<bool>,
 
@@ -70,21 +110,123 @@ pub struct Cli {
     #[clap(long)]
     pub source_key: Option<String>,
 
+    /// Version of --source-key used by download, delete, copy-single, move-single and copy-bucket-to-bucket
+    /// instead of the current version. Deleting a version removes it permanently
+    #[clap(long)]
+    pub version_id: Option<String>,
+
+    /// File with a 'key,version id' pair on each line, e.g. the saved output of list-object-versions,
+    /// for downloading, copying, moving or permanently deleting those versions. The separator is the one of --sep.
+    /// copy-single and move-single copy the versions into the folder --target-key
+    #[clap(long)]
+    pub versions_file: Option<String>,
+
     /// Target key for copy or move operations
     #[clap(long)]
     pub target_key: Option<String>,
 
+    /// Target key template for copy-multiple and move-multiple, using the capture groups
+    /// of --list-regex-pattern, e.g. 'archive/$1/$2.json' or 'archive/${1}_old'
+    #[clap(long)]
+    pub rename_template: Option<String>,
+
+    /// Whether copies and moves keep the user metadata of the source or replace it with --metadata
+    #[clap(long, arg_enum, value_name = "copy")]
+    pub metadata_directive: Option<CopyDirective>,
+
+    /// User metadata set by copies and moves with --metadata-directive replace, can be repeated
+    #[clap(long, value_name = "key=value")]
+    pub metadata: Vec<String>,
+
+    /// Whether copies and moves keep the tags of the source or replace them with --tag
+    #[clap(long, arg_enum, value_name = "copy")]
+    pub tagging_directive: Option<CopyDirective>,
+
+    /// Tag set by copies and moves with --tagging-directive replace, can be repeated
+    #[clap(long, value_name = "key=value")]
+    pub tag: Vec<String>,
+
+    /// Storage class of copied objects, like 'STANDARD_IA' or 'GLACIER_IR'. Copies keep the storage class of the source without it
+    #[clap(long)]
+    pub storage_class: Option<String>,
+
+    /// ACL of copied objects: 'preserve' to carry over the grants of the source or a canned ACL like 'bucket-owner-full-control'
+    #[clap(long)]
+    pub acl: Option<String>,
+
     /// Used to upload files to a flat or otherwise recursive structure.
     #[clap(long, short, action)]
     pub flatten: bool,
 
+    /// What to do when the destination of a download or upload already exists.
+    /// newer-only compares the local modification time with the last modified date on S3
+    #[clap(long, arg_enum, value_name = "overwrite")]
+    pub if_exists: Option<ExistsPolicy>,
+
+    /// Leading key prefix dropped when mapping keys to local paths on download, e.g. 'archive/2022/'.
+    /// Also dropped from the keys copied by sync-bucket-to-bucket
+    #[clap(long)]
+    pub strip_prefix: Option<String>,
+
+    /// The local folder kept in step with the keys below --prefix by the sync mode
+    #[clap(long)]
+    pub local_folder: Option<String>,
+
+    /// Direction of the sync mode: upload copies local changes to S3, download copies S3 changes to the local folder
+    #[clap(long, arg_enum)]
+    pub sync_direction: Option<SyncDirection>,
+
+    /// Used by sync to remove files or objects on the destination side which do not exist on the source side
+    #[clap(long, action)]
+    pub delete: bool,
+
+    /// Used by sync to compare files of the same size by their MD5 and the ETag instead of the modification time.
+    /// Objects encrypted with SSE-KMS, whose ETag is not their MD5, are still compared by modification time
+    #[clap(long, action)]
+    pub checksum: bool,
+
+    /// Point in time restored by restore-to-time, like '2022-10-05T12:30:00Z' or '2022-10-05 12:30:00' in UTC
+    #[clap(long)]
+    pub timestamp: Option<String>,
+
+    /// Number of noncurrent versions per key kept by prune-versions. Delete markers do not count as kept versions
+    #[clap(long)]
+    pub keep_versions: Option<usize>,
+
+    /// Used by prune-versions to only delete versions which became noncurrent more than this number of days ago
+    #[clap(long)]
+    pub older_than_days: Option<u64>,
+
+    /// Used to only print what restore-to-time, undelete or prune-versions would change
+    #[clap(long, action)]
+    pub dry_run: bool,
+
+    /// Used by delete-bucket to first delete all object versions, delete markers and unfinished
+    /// multipart uploads, after asking for confirmation
+    #[clap(long, action)]
+    pub force: bool,
+
     /// Used to filter buckets strictly
     #[clap(long, action)]
     pub strict_bucket: bool,
 
-    #[clap(long, value_name = "2")]
+    /// Minimum part size in MiB for multipart uploads, between 5 and 5120.
+    /// It is raised automatically when the file would otherwise need more than 10000 parts.
+    /// Also the size of the ranges fetched in parallel when downloading large objects
+    #[clap(long, value_name = "5")]
     pub chunk_size: Option<u64>,
 
+    /// Files larger than this size in MiB are uploaded in parts by the upload mode
+    /// and downloaded as parallel byte ranges by the download mode
+    #[clap(long, value_name = "100")]
+    pub multipart_threshold: Option<u64>,
+
+    /// Number of objects processed in parallel by download, delete, copy-multiple and move-multiple.
+    /// Also the number of parts uploaded or byte ranges downloaded in parallel for large files
+    /// and the number of byte ranges copied in parallel for sources larger than 5 GB
+    #[clap(long, value_name = "1")]
+    pub concurrency: Option<usize>,
+
     #[clap(long)]
     pub upload_file: Option<String>
 
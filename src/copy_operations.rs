@@ -1,21 +1,29 @@
+use std::collections::{BTreeMap, HashSet};
+
 use aws_sdk_s3::{Client, Error};
 use aws_sdk_s3::model::Object;
+use aws_sdk_s3::output::HeadObjectOutput;
+use aws_smithy_http::result::SdkError;
 use aws_client::{ClientBucket, OutputPrinter};
 use crate::delete_object;
+use aws_client::file_delete::delete_versions_batch;
+use crate::list_objects::{extract_list_regex_pattern, ObjectPages};
+use crate::checksum::trim_etag;
+use crate::rename_template::RenameTemplate;
 use aws_client::cli::Cli;
+use aws_client::object_copy::{copy_object_any_size, SOURCE_ETAG_METADATA};
+use aws_client::worker_pool::WorkerPool;
 
 pub(crate) async fn copy_object(
     client_bucket: &ClientBucket,
     output_printer: &dyn OutputPrinter,
 ) -> Result<(), Error> {
-    let client = &client_bucket.client;
-    let bucket_name = &client_bucket.bucket_name;
     let args = &client_bucket.args;
     let source_key = extract_source_key(output_printer, args);
 
     let target_key = extract_target_key(output_printer, args);
 
-    let res = copy_from_key_to_target(client, bucket_name, &source_key, &target_key).await;
+    let res = copy_from_key_to_target(client_bucket, &source_key, args.version_id.as_deref(), &target_key).await;
 
     match res {
         Ok(()) => {
@@ -43,21 +51,12 @@ pub(crate) fn extract_source_key(output_printer: &dyn OutputPrinter, args: &Cli)
     source_key.clone()
 }
 
-pub(crate) async fn copy_from_key_to_target(client: &Client,
-                                            bucket_name: &String,
+pub(crate) async fn copy_from_key_to_target(client_bucket: &ClientBucket,
                                             source_key: &String,
+                                            source_version_id: Option<&str>,
                                             target_key: &String) -> Result<(), Error> {
-    let source_bucket_and_object = format!("{}/{}", bucket_name, source_key);
-
-    client
-        .copy_object()
-        .copy_source(source_bucket_and_object)
-        .bucket(bucket_name)
-        .key(target_key)
-        .send()
-        .await?;
-
-    Ok(())
+    let bucket_name = &client_bucket.bucket_name;
+    copy_object_any_size(client_bucket, bucket_name, source_key, source_version_id, bucket_name, target_key).await
 }
 
 pub(crate) fn extract_target_key(output_printer: &dyn OutputPrinter, args: &Cli) -> String {
@@ -82,7 +81,14 @@ pub(crate) async fn move_object(
     match copy_object(client_bucket, output_printer).await {
         Ok(()) => {
             let source_key = &args.source_key.as_ref().unwrap();
-            delete_object(&client_bucket, source_key, output_printer).await;
+            match &args.version_id {
+                // Moving a version removes that version rather than hiding the key behind a delete marker
+                Some(version_id) => {
+                    let version = (source_key.to_string(), Some(version_id.clone()));
+                    delete_versions_batch(client_bucket, vec![version], output_printer).await;
+                }
+                None => delete_object(&client_bucket, source_key, output_printer).await
+            }
         }
         Err(e) => {
             let source_key = &args.source_key.as_ref().unwrap();
@@ -98,8 +104,7 @@ pub(crate) async fn copy_multiple_process_obj(
     output_printer: &dyn OutputPrinter,
 ) {
     let (source_key, target_key) = extract_source_target_keys(&client_bucket, obj, output_printer);
-    let res = copy_from_key_to_target(&client_bucket.client, &client_bucket.bucket_name,
-                                      &source_key, &target_key).await;
+    let res = copy_from_key_to_target(client_bucket, &source_key, None, &target_key).await;
     match res {
         Ok(_) => {
             output_printer.ok_output(format!("Copied {} to {}",
@@ -111,7 +116,7 @@ pub(crate) async fn copy_multiple_process_obj(
     }
 }
 
-fn handle_copy_error(output_printer: &dyn OutputPrinter, source_key: String, target_key: String, e: Error) {
+pub(crate) fn handle_copy_error(output_printer: &dyn OutputPrinter, source_key: String, target_key: String, e: Error) {
     output_printer.err_output(format!("Failed to copy {} to {}",
                                       source_key, target_key).as_str());
     output_printer.err_output(format!("Error {:?}", e).as_str());
@@ -131,8 +136,7 @@ pub(crate) async fn move_multiple_process_obj(
     output_printer: &dyn OutputPrinter,
 ) {
     let (source_key, target_key) = extract_source_target_keys(&client_bucket, obj, output_printer);
-    let res = copy_from_key_to_target(&client_bucket.client, &client_bucket.bucket_name,
-                                      &source_key, &target_key).await;
+    let res = copy_from_key_to_target(client_bucket, &source_key, None, &target_key).await;
     match res {
         Ok(_) => {
             output_printer.ok_output(format!("Copied {} to {}",
@@ -143,4 +147,201 @@ pub(crate) async fn move_multiple_process_obj(
             handle_copy_error(output_printer, source_key, target_key, e);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Copies, or with `delete_source` moves, every listed object to the key built from `--rename-template`.
+/// All target keys are worked out before the first copy, so that nothing is copied when two
+/// sources would end up with the same target key.
+pub(crate) async fn rename_multiple(client_bucket: &ClientBucket,
+                                    output_printer: &dyn OutputPrinter,
+                                    delete_source: bool) -> Result<(), String> {
+    let template = RenameTemplate::parse(client_bucket.args.rename_template.as_ref()
+        .ok_or("Please specify the rename template")?)?;
+    let re = extract_list_regex_pattern(&client_bucket);
+    template.check_groups(&re)?;
+    let mut renames: Vec<(String, String)> = Vec::new();
+    let mut object_pages = ObjectPages::new(client_bucket);
+    while let Some(page) = object_pages.next_page().await
+        .map_err(|e| format!("Could not list bucket: {:?}", e))? {
+        for obj in page {
+            let source_key = obj.key().unwrap_or_default();
+            if let Ok(Some(captures)) = re.captures(source_key) {
+                renames.push((source_key.to_string(), template.expand(&captures)));
+            }
+        }
+    }
+    object_pages.print_stats(output_printer);
+
+    let collisions = find_collisions(&renames);
+    if !collisions.is_empty() {
+        for collision in &collisions {
+            output_printer.err_output(collision.as_str());
+        }
+        return Err(format!("Nothing was copied because of {} colliding target keys", collisions.len()));
+    }
+    let mut worker_pool = WorkerPool::new(client_bucket.args.concurrency);
+    for (source_key, target_key) in &renames {
+        worker_pool.submit(rename_object(client_bucket, source_key, target_key, delete_source, output_printer)).await;
+    }
+    while worker_pool.next().await.is_some() {}
+    Ok(())
+}
+
+async fn rename_object(client_bucket: &ClientBucket,
+                       source_key: &String,
+                       target_key: &String,
+                       delete_source: bool,
+                       output_printer: &dyn OutputPrinter) {
+    match copy_from_key_to_target(client_bucket, source_key, None, target_key).await {
+        Ok(_) => {
+            output_printer.ok_output(format!("Copied {} to {}",
+                                             source_key, target_key).as_str());
+            if delete_source {
+                delete_object(client_bucket, source_key.as_str(), output_printer).await;
+            }
+        }
+        Err(e) => {
+            handle_copy_error(output_printer, source_key.to_string(), target_key.to_string(), e);
+        }
+    }
+}
+
+/// Target keys shared by several sources, and target keys which are also a source key,
+/// as these would be overwritten by a copy or removed by a move.
+fn find_collisions(renames: &[(String, String)]) -> Vec<String> {
+    let mut sources_by_target: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for (source_key, target_key) in renames {
+        sources_by_target.entry(target_key.as_str()).or_default().push(source_key.as_str());
+    }
+    let source_keys: HashSet<&str> = renames.iter().map(|(source_key, _)| source_key.as_str()).collect();
+    let mut collisions = Vec::new();
+    for (target_key, sources) in sources_by_target {
+        if sources.len() > 1 {
+            collisions.push(format!("{} is the target of {}", target_key, sources.join(", ")));
+        } else if source_keys.contains(target_key) {
+            collisions.push(format!("{} is the target of {} and also a source", target_key, sources[0]));
+        }
+    }
+    collisions
+}
+
+/// Copies a listed object to `--target-bucket`, below `--target-folder` if given, unless the
+/// object there is a copy of the same content. The copy records the ETag of its source for this check.
+pub(crate) async fn sync_bucket_process_obj(
+    client_bucket: &ClientBucket,
+    obj: Object,
+    output_printer: &dyn OutputPrinter,
+) {
+    let args = &client_bucket.args;
+    let source_bucket = &client_bucket.bucket_name;
+    let target_bucket = args.target_bucket.as_ref()
+        .expect("Target bucket is missing. Please specify the target bucket.");
+    let source_key = obj.key().unwrap();
+    let target_key = target_bucket_key(source_key, args.target_folder.as_deref(), args.strip_prefix.as_deref());
+    match target_is_up_to_date(&client_bucket.client, target_bucket, target_key.as_str(), &obj).await {
+        Ok(true) => {
+            output_printer.ok_output(format!("Skipped {} as {}/{} is up to date",
+                                             source_key, target_bucket, target_key).as_str());
+            return;
+        }
+        Ok(false) => {}
+        Err(e) => {
+            output_printer.err_output(format!("Cannot check {}/{} due to {:?}", target_bucket, target_key, e).as_str());
+            return;
+        }
+    }
+    let res = copy_object_any_size(client_bucket, source_bucket, source_key, None,
+                                   target_bucket, target_key.as_str()).await;
+    match res {
+        Ok(_) => {
+            output_printer.ok_output(format!("Copied {}/{} to {}/{}",
+                                             source_bucket, source_key, target_bucket, target_key).as_str());
+        }
+        Err(e) => {
+            handle_copy_error(output_printer, format!("{}/{}", source_bucket, source_key),
+                              format!("{}/{}", target_bucket, target_key), e);
+        }
+    }
+}
+
+/// The key of an object in the target bucket: the source key without `strip_prefix`, below `target_folder`.
+fn target_bucket_key(source_key: &str, target_folder: Option<&str>, strip_prefix: Option<&str>) -> String {
+    let relative_key = strip_prefix
+        .and_then(|prefix| source_key.strip_prefix(prefix))
+        .unwrap_or(source_key);
+    match target_folder {
+        Some(tf) => format!("{}/{}", tf.trim_end_matches('/'), relative_key),
+        None => relative_key.to_string()
+    }
+}
+
+async fn target_is_up_to_date(client: &Client, target_bucket: &str, target_key: &str, obj: &Object) -> Result<bool, Error> {
+    let res = client
+        .head_object()
+        .bucket(target_bucket)
+        .key(target_key)
+        .send()
+        .await;
+    match res {
+        Ok(head) => Ok(target_matches_source(&head, obj)),
+        Err(SdkError::ServiceError { err, .. }) if err.is_not_found() => Ok(false),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// The target has the size of the source and either its ETag or, when one of them was uploaded
+/// in parts, the ETag of the source recorded in its metadata by an earlier sync.
+fn target_matches_source(head: &HeadObjectOutput, obj: &Object) -> bool {
+    let source_e_tag = match obj.e_tag() {
+        Some(e_tag) => trim_etag(e_tag),
+        None => return false
+    };
+    let recorded_e_tag = head.metadata().and_then(|metadata| metadata.get(SOURCE_ETAG_METADATA));
+    head.content_length() == obj.size()
+        && (head.e_tag().map(trim_etag) == Some(source_e_tag) || recorded_e_tag.map(|e| e.as_str()) == Some(source_e_tag))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_target_bucket_key() {
+        assert_eq!(target_bucket_key("data/2022/report.csv", None, None), "data/2022/report.csv");
+        assert_eq!(target_bucket_key("data/2022/report.csv", Some("refresh/"), None), "refresh/data/2022/report.csv");
+        assert_eq!(target_bucket_key("data/2022/report.csv", Some("refresh"), Some("data/")), "refresh/2022/report.csv");
+    }
+
+    #[test]
+    fn test_target_matches_source() {
+        let source = Object::builder().e_tag("\"9b2cf535f27731c974343645a3985328-3\"").size(1536 * 1024 * 1024).build();
+        // A copy in 512 MiB parts gets an ETag of its own, so only the recorded source ETag matches
+        let copied = |e_tag: &str, size: i64| HeadObjectOutput::builder()
+            .e_tag(e_tag)
+            .content_length(size)
+            .metadata(SOURCE_ETAG_METADATA, "9b2cf535f27731c974343645a3985328-3")
+            .build();
+        assert!(target_matches_source(&copied("\"1f0ee5a5d4bd1ca4a5fe3b1f4fd2a5c3-3\"", 1536 * 1024 * 1024), &source));
+        assert!(!target_matches_source(&copied("\"1f0ee5a5d4bd1ca4a5fe3b1f4fd2a5c3-3\"", 1024), &source));
+        let unrecorded = HeadObjectOutput::builder()
+            .e_tag("\"1f0ee5a5d4bd1ca4a5fe3b1f4fd2a5c3-3\"")
+            .content_length(1536 * 1024 * 1024)
+            .build();
+        assert!(!target_matches_source(&unrecorded, &source));
+        let same_e_tag = HeadObjectOutput::builder()
+            .e_tag("\"9b2cf535f27731c974343645a3985328-3\"")
+            .content_length(1536 * 1024 * 1024)
+            .build();
+        assert!(target_matches_source(&same_e_tag, &source));
+    }
+
+    #[test]
+    fn test_find_collisions() {
+        let rename = |source: &str, target: &str| (source.to_string(), target.to_string());
+        assert!(find_collisions(&[rename("a/1.csv", "b/1.csv"), rename("a/2.csv", "b/2.csv")]).is_empty());
+        assert_eq!(find_collisions(&[rename("a/1.csv", "b/x.csv"), rename("c/1.csv", "b/x.csv")]),
+                   vec!["b/x.csv is the target of a/1.csv, c/1.csv".to_string()]);
+        assert_eq!(find_collisions(&[rename("a/1.csv", "a/2.csv"), rename("a/2.csv", "a/3.csv")]).len(), 1);
+    }
+}
@@ -1,18 +1,81 @@
-use aws_sdk_s3::model::{Delete, ObjectIdentifier};
-use aws_client::OutputPrinter;
-use aws_client::ClientBucket;
+use aws_sdk_s3::model::{Delete, Object, ObjectIdentifier};
+use crate::OutputPrinter;
+use crate::ClientBucket;
 
-pub(crate) async fn delete_object(client_bucket: &ClientBucket,
-                                  key: &str,
-                                  output_printer: &dyn OutputPrinter) {
-    let client = &client_bucket.client;
-    let bucket_name = &client_bucket.bucket_name;
+/// Maximum number of keys accepted by a single `DeleteObjects` request.
+pub const MAX_DELETE_BATCH: usize = 1000;
 
-    let mut delete_objects: Vec<ObjectIdentifier> = vec![];
+pub async fn delete_object(client_bucket: &ClientBucket,
+                           key: &str,
+                           output_printer: &dyn OutputPrinter) {
     let obj_id = ObjectIdentifier::builder()
         .set_key(Some(key.to_string()))
         .build();
-    delete_objects.push(obj_id);
+    delete_identifiers(client_bucket, vec![obj_id], true, output_printer).await;
+}
+
+pub async fn delete_objects_batch(client_bucket: &ClientBucket,
+                                  objects: Vec<Object>,
+                                  output_printer: &dyn OutputPrinter) {
+    let delete_objects: Vec<ObjectIdentifier> = objects.iter()
+        .map(|obj| ObjectIdentifier::builder()
+            .set_key(obj.key().map(|k| k.to_string()))
+            .build())
+        .collect();
+    delete_identifiers(client_bucket, delete_objects, true, output_printer).await;
+}
+
+/// Deletes the given versions of keys. A key without a version id gets a delete marker in a versioned bucket.
+/// Returns the versions which were deleted.
+pub async fn delete_versions_batch(client_bucket: &ClientBucket,
+                                   versions: Vec<(String, Option<String>)>,
+                                   output_printer: &dyn OutputPrinter) -> Vec<(String, Option<String>)> {
+    delete_identifiers(client_bucket, version_identifiers(versions), true, output_printer).await
+}
+
+/// Like `delete_versions_batch`, but for any number of versions, which are deleted in batches of
+/// up to `MAX_DELETE_BATCH`. Failures are always reported, each deletion only with `report_deleted`.
+pub async fn delete_versions_in_batches(client_bucket: &ClientBucket,
+                                        mut versions: Vec<(String, Option<String>)>,
+                                        report_deleted: bool,
+                                        output_printer: &dyn OutputPrinter) -> Vec<(String, Option<String>)> {
+    let mut deleted_versions = Vec::new();
+    while !versions.is_empty() {
+        let rest = versions.split_off(versions.len().min(MAX_DELETE_BATCH));
+        let batch = std::mem::replace(&mut versions, rest);
+        deleted_versions.extend(delete_identifiers(client_bucket, version_identifiers(batch), report_deleted, output_printer).await);
+    }
+    deleted_versions
+}
+
+/// Deletes the current version of any number of objects, in batches of up to `MAX_DELETE_BATCH`.
+pub async fn delete_objects_in_batches(client_bucket: &ClientBucket,
+                                       objects: Vec<Object>,
+                                       output_printer: &dyn OutputPrinter) {
+    let versions = objects.iter()
+        .map(|obj| (obj.key().unwrap_or_default().to_string(), None))
+        .collect();
+    delete_versions_in_batches(client_bucket, versions, true, output_printer).await;
+}
+
+fn version_identifiers(versions: Vec<(String, Option<String>)>) -> Vec<ObjectIdentifier> {
+    versions.into_iter()
+        .map(|(key, version_id)| ObjectIdentifier::builder()
+            .key(key)
+            .set_version_id(version_id)
+            .build())
+        .collect()
+}
+
+/// Deletes up to `MAX_DELETE_BATCH` objects or versions with a single request. Failures are always
+/// reported, each deletion only with `report_deleted`. Returns the versions which were deleted.
+async fn delete_identifiers(client_bucket: &ClientBucket,
+                            delete_objects: Vec<ObjectIdentifier>,
+                            report_deleted: bool,
+                            output_printer: &dyn OutputPrinter) -> Vec<(String, Option<String>)> {
+    let client = &client_bucket.client;
+    let bucket_name = &client_bucket.bucket_name;
+    let requested = delete_objects.len();
 
     let delete_res = client
         .delete_objects()
@@ -21,13 +84,33 @@ pub(crate) async fn delete_object(client_bucket: &ClientBucket,
         .send()
         .await;
 
+    let mut deleted_versions = Vec::new();
     match delete_res {
-        Ok(_) => {
-            output_printer.ok_output(format!("Deleted successfully {}",
-                                             key.to_string()).as_str());
+        Ok(output) => {
+            for deleted in output.deleted().unwrap_or_default() {
+                deleted_versions.push((deleted.key().unwrap_or_default().to_string(),
+                                       deleted.version_id().map(|v| v.to_string())));
+                if report_deleted {
+                    output_printer.ok_output(format!("Deleted successfully {}{}",
+                                                     deleted.key().unwrap_or_default(),
+                                                     version_suffix(deleted.version_id())).as_str());
+                }
+            }
+            for error in output.errors().unwrap_or_default() {
+                output_printer.err_output(format!("Delete failed {}{}: {} {}",
+                                                  error.key().unwrap_or_default(),
+                                                  version_suffix(error.version_id()),
+                                                  error.code().unwrap_or_default(),
+                                                  error.message().unwrap_or_default()).as_str());
+            }
         }
         Err(e) => {
-            output_printer.ok_output(format!("Delete failed {:?}", e).as_str());
+            output_printer.err_output(format!("Delete of {} keys failed {:?}", requested, e).as_str());
         }
     }
-}
\ No newline at end of file
+    deleted_versions
+}
+
+fn version_suffix(version_id: Option<&str>) -> String {
+    version_id.map(|v| format!(" version {}", v)).unwrap_or_default()
+}
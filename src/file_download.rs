@@ -1,54 +1,439 @@
 use std::fs;
-use std::path::PathBuf;
-use aws_smithy_http::byte_stream::{AggregatedBytes};
+use std::io::{SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use aws_sdk_s3::model::Object;
+use aws_smithy_http::byte_stream::ByteStream;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use aws_client::OutputPrinter;
 
 use aws_client::ClientBucket;
+use aws_client::cli::ExistsPolicy;
+use aws_client::worker_pool::WorkerPool;
+
+use crate::checksum::{etag_is_md5, etag_part_count, trim_etag, EtagHasher};
+use crate::transfer_policy::{local_stats, should_transfer, FileStats};
+use crate::upload_files::{DEFAULT_CHUNK_SIZE, DEFAULT_MULTIPART_THRESHOLD, MIB};
 
 pub(crate) async fn download_object(client_bucket: &ClientBucket,
-                                    key: &str,
+                                    obj: &Object,
                                     output_printer: &dyn OutputPrinter) {
-    let client = &client_bucket.client;
-    let bucket_name = &client_bucket.bucket_name;
+    let key = obj.key().unwrap();
     let target_folder_str = &client_bucket.args.target_folder.as_ref().expect("Please define the target folder for download");
     let path: PathBuf = PathBuf::from(*target_folder_str);
     let flatten = &client_bucket.args.flatten;
-    let resp = client
+    let strip_prefix = client_bucket.args.strip_prefix.as_deref();
+    if key.ends_with('/') {
+        // Folder placeholder objects have no content to download
+        return;
+    }
+    if let Some(new_path) = key_to_path(&path, key, *flatten, strip_prefix) {
+        let if_exists = client_bucket.args.if_exists.unwrap_or(ExistsPolicy::Overwrite);
+        if !should_transfer(if_exists, &FileStats::from_object(obj), local_stats(&new_path).as_ref()) {
+            output_printer.ok_output(format!("Skipped {} as {:?} already exists", key, new_path).as_str());
+            return;
+        }
+        download_object_to(client_bucket, obj, None, &new_path, output_printer).await;
+    } else {
+        output_printer.err_output(format!("Cannot map {} to a local path", key).as_str());
+    }
+}
+
+/// Downloads the object to `new_path`, with ranged requests if it is larger than `--multipart-threshold`.
+/// Without a version id the current version is downloaded.
+pub(crate) async fn download_object_to(client_bucket: &ClientBucket,
+                                       obj: &Object,
+                                       version_id: Option<&str>,
+                                       new_path: &Path,
+                                       output_printer: &dyn OutputPrinter) {
+    let key = obj.key().unwrap_or_default();
+    let parent_dir = new_path.parent().unwrap();
+    if let Err(e) = fs::create_dir_all(parent_dir) {
+        output_printer.err_output(format!("Cannot download {} as {:?} cannot be created: {}", key, parent_dir, e).as_str());
+        return;
+    }
+    let multipart_threshold = client_bucket.args.multipart_threshold
+        .unwrap_or(DEFAULT_MULTIPART_THRESHOLD) * MIB;
+    let res = if obj.size() as u64 > multipart_threshold {
+        download_ranges_to_path(client_bucket, obj, version_id, new_path, output_printer).await
+    } else {
+        download_to_path(client_bucket, obj, version_id, new_path, output_printer).await
+    };
+    match res {
+        Ok(()) => {
+            output_printer.ok_output(format!("Downloaded successfully {} to {:?}", key, new_path).as_str());
+        }
+        Err(msg) => {
+            output_printer.err_output(msg.as_str());
+        }
+    }
+}
+
+/// Maps a key to a file below `target_folder`. Without `flatten` the key hierarchy is mirrored,
+/// after dropping `strip_prefix` from the start of the key. Keys with segments which could point
+/// outside of the target folder, like `..`, `C:` or anything with a Windows path separator, are not mapped.
+pub(crate) fn key_to_path(target_folder: &Path, key: &str, flatten: bool, strip_prefix: Option<&str>) -> Option<PathBuf> {
+    let relative_key = strip_prefix
+        .and_then(|prefix| key.strip_prefix(prefix))
+        .unwrap_or(key);
+    let segments: Vec<&str> = relative_key.split('/').filter(|segment| !segment.is_empty()).collect();
+    if segments.is_empty() || !segments.iter().all(|segment| is_plain_segment(segment)) {
+        return None;
+    }
+    if flatten {
+        return Some(target_folder.join(segments.last()?));
+    }
+    Some(segments.iter().fold(target_folder.to_path_buf(), |path, segment| path.join(segment)))
+}
+
+/// True if the segment is a single file or folder name on every platform.
+fn is_plain_segment(segment: &str) -> bool {
+    let mut components = Path::new(segment).components();
+    !segment.contains(['\\', ':'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+/// Streams the object into a temporary file next to `new_path` and only renames it into place
+/// once it is complete and matches its ETag. A partial file left behind by an interrupted run
+/// is continued with a range request, as long as the object still has the same ETag.
+async fn download_to_path(client_bucket: &ClientBucket,
+                          obj: &Object,
+                          version_id: Option<&str>,
+                          new_path: &Path,
+                          output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let key = obj.key().unwrap();
+    let listed_e_tag = obj.e_tag().unwrap_or_default();
+    let temp_path = temp_download_path(new_path);
+    let state_path = download_state_path(&temp_path);
+    let mut resume_from = match read_download_state(&state_path, listed_e_tag) {
+        // The last byte is always fetched again, so that even a complete partial file gets a response to check
+        Some(_) => fs::metadata(&temp_path).map(|m| m.len()).unwrap_or_default()
+            .min((obj.size() as u64).saturating_sub(1)),
+        None => 0
+    };
+    let get_object = |from: u64| {
+        let request = client_bucket.client
+            .get_object()
+            .bucket(&client_bucket.bucket_name)
+            .key(key)
+            .set_version_id(version_id.map(|v| v.to_string()));
+        if from > 0 {
+            request.range(format!("bytes={}-", from)).if_match(listed_e_tag)
+        } else {
+            request
+        }
+    };
+    if resume_from > 0 {
+        output_printer.info_output(format!("Resuming download of {} from byte {}", key, resume_from).as_str());
+    }
+    let resp = match get_object(resume_from).send().await {
+        Ok(resp) => resp,
+        Err(e) if resume_from > 0 => {
+            output_printer.info_output(format!("Cannot resume download of {}, starting again: {:?}", key, e).as_str());
+            resume_from = 0;
+            get_object(0).send().await.map_err(|e| format!("Cannot download {} due to {:?}", key, e))?
+        }
+        Err(e) => {
+            return Err(format!("Cannot download {} due to {:?}", key, e));
+        }
+    };
+    let e_tag = if resume_from > 0 { listed_e_tag.to_string() } else { resp.e_tag().unwrap_or_default().to_string() };
+    let mut hasher = if etag_is_md5(resp.server_side_encryption(), resp.sse_customer_algorithm()) {
+        Some(EtagHasher::new(etag_part_size(client_bucket, key, version_id, &e_tag).await?))
+    } else {
+        None
+    };
+    let mut file = if resume_from > 0 {
+        if let Some(hasher) = hasher.as_mut() {
+            hash_file(&temp_path, resume_from, hasher).await?;
+        }
+        let mut file = OpenOptions::new().write(true).open(&temp_path).await.map_err(|e| e.to_string())?;
+        file.set_len(resume_from).await.map_err(|e| e.to_string())?;
+        file.seek(SeekFrom::Start(resume_from)).await.map_err(|e| e.to_string())?;
+        file
+    } else {
+        start_download_state(&state_path, &e_tag)?;
+        File::create(&temp_path).await.map_err(|e| e.to_string())?
+    };
+    write_stream(resp.body, &mut file, hasher.as_mut()).await
+        .map_err(|e| format!("Cannot download {}, run again to resume: {}", key, e))?;
+    let res = match hasher {
+        Some(hasher) => verify_etag(key, &e_tag, hasher),
+        None => Ok(())
+    };
+    finish_download(res, &temp_path, new_path)
+}
+
+/// Downloads a large object with concurrent ranged requests of `--chunk-size` MiB, each written
+/// at its offset in a preallocated temporary file. Ranges already written by an interrupted run
+/// are skipped, as long as the object still has the same ETag.
+async fn download_ranges_to_path(client_bucket: &ClientBucket,
+                                 obj: &Object,
+                                 version_id: Option<&str>,
+                                 new_path: &Path,
+                                 output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let key = obj.key().unwrap();
+    let e_tag = obj.e_tag().unwrap_or_default();
+    let size = obj.size() as u64;
+    let part_size = client_bucket.args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1) * MIB;
+    let temp_path = temp_download_path(new_path);
+    let state_path = download_state_path(&temp_path);
+    let partial_size = fs::metadata(&temp_path).map(|m| m.len()).ok();
+    let completed = match read_download_state(&state_path, e_tag) {
+        Some(completed) if partial_size == Some(size) => {
+            output_printer.info_output(format!("Resuming download of {} with {} ranges already downloaded",
+                                               key, completed.len()).as_str());
+            completed
+        }
+        _ => {
+            start_download_state(&state_path, e_tag)?;
+            let file = File::create(&temp_path).await.map_err(|e| e.to_string())?;
+            file.set_len(size).await.map_err(|e| e.to_string())?;
+            Vec::new()
+        }
+    };
+    download_ranges(client_bucket, obj, version_id, part_size, &temp_path, &state_path, completed).await
+        .map_err(|e| format!("Cannot download {}, run again to resume: {}", key, e))?;
+    // Asked for separately, as a rerun may find all ranges downloaded already
+    let res = match etag_hasher(client_bucket, key, version_id, e_tag).await? {
+        Some(mut hasher) => {
+            hash_file(&temp_path, size, &mut hasher).await?;
+            verify_etag(key, e_tag, hasher)
+        }
+        None => Ok(())
+    };
+    finish_download(res, &temp_path, new_path)
+}
+
+/// Fetches all ranges not in `completed` with up to `--concurrency` requests in flight, recording each
+/// finished range in the state file.
+async fn download_ranges(client_bucket: &ClientBucket,
+                         obj: &Object,
+                         version_id: Option<&str>,
+                         part_size: u64,
+                         temp_path: &Path,
+                         state_path: &Path,
+                         completed: Vec<(u64, u64)>) -> Result<(), String> {
+    let key = obj.key().unwrap();
+    let e_tag = obj.e_tag().unwrap_or_default();
+    let size = obj.size() as u64;
+    let mut worker_pool = WorkerPool::new(client_bucket.args.concurrency);
+    let record_range = |finished: Result<(u64, u64), String>| -> Result<(), String> {
+        let (start, end) = finished?;
+        append_download_range(state_path, start, end).map_err(|e| e.to_string())
+    };
+    let mut start = 0;
+    while start < size {
+        let end = (start + part_size).min(size) - 1;
+        if !completed.contains(&(start, end)) {
+            let range = download_range(client_bucket, key, version_id, e_tag, start, end, temp_path);
+            if let Some(finished) = worker_pool.submit(range).await {
+                record_range(finished)?;
+            }
+        }
+        start = end + 1;
+    }
+    while let Some(finished) = worker_pool.next().await {
+        record_range(finished)?;
+    }
+    Ok(())
+}
+
+/// Writes the bytes `start..=end` of the object at the same offset of the temporary file.
+async fn download_range(client_bucket: &ClientBucket,
+                        key: &str,
+                        version_id: Option<&str>,
+                        e_tag: &str,
+                        start: u64,
+                        end: u64,
+                        temp_path: &Path) -> Result<(u64, u64), String> {
+    let obj = client_bucket.client
         .get_object()
-        .bucket(bucket_name)
+        .bucket(&client_bucket.bucket_name)
         .key(key)
+        .set_version_id(version_id.map(|v| v.to_string()))
+        .range(format!("bytes={}-{}", start, end))
+        .if_match(e_tag)
         .send()
-        .await;
-    match resp {
-        Ok(obj) => {
-            let stream = obj.body.collect().await;
-            let splits = key.split("/");
-            let last_split_option = splits.last();
-            let last = last_split_option.unwrap();
-            if last.len() > 0 {
-                let new_path = if *flatten { path.join(PathBuf::from(last)) } else {
-                    path.join(PathBuf::from(key)).join(PathBuf::from(last))
-                };
-                let parent_dir = new_path.parent().unwrap();
-                if !parent_dir.exists() {
-                    fs::create_dir_all(parent_dir.clone())
-                        .expect(format!("Could not create {:?}", parent_dir.clone()).as_str());
-                }
-                write_file(key, output_printer, &mut stream.unwrap(), new_path).await;
-            }
+        .await
+        .map_err(|e| format!("{:?}", e))?;
+    let mut file = OpenOptions::new().write(true).open(temp_path).await.map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(start)).await.map_err(|e| e.to_string())?;
+    write_stream(obj.body, &mut file, None).await?;
+    Ok((start, end))
+}
+
+/// Feeds the first `length` bytes of the file to the hasher.
+async fn hash_file(path: &Path, length: u64, hasher: &mut EtagHasher) -> Result<(), String> {
+    let file = File::open(path).await.map_err(|e| e.to_string())?;
+    let mut file = file.take(length);
+    let mut buffer = vec![0; MIB as usize];
+    loop {
+        let read = file.read(&mut buffer).await.map_err(|e| e.to_string())?;
+        if read == 0 {
+            break;
         }
-        Err(e) => {
-            output_printer.err_output(format!("Cannot download {} due to {:?}", key, e).as_str());
+        hasher.update(&buffer[..read]);
+    }
+    Ok(())
+}
+
+/// Moves a complete download into place. A download failing its ETag check is removed, so that
+/// it is not resumed by the next run.
+fn finish_download(res: Result<(), String>, temp_path: &Path, new_path: &Path) -> Result<(), String> {
+    let _ = fs::remove_file(download_state_path(temp_path));
+    if let Err(msg) = res {
+        let _ = fs::remove_file(temp_path);
+        return Err(msg);
+    }
+    fs::rename(temp_path, new_path).map_err(|e| format!("Cannot move {:?} to {:?}: {}", temp_path, new_path, e))
+}
+
+fn temp_download_path(new_path: &Path) -> PathBuf {
+    let mut temp_name = new_path.as_os_str().to_os_string();
+    temp_name.push(".part");
+    PathBuf::from(temp_name)
+}
+
+/// The state file of a partial download holds the ETag of the object on its first line,
+/// followed by one `range=<start>-<end>` line for every range already written.
+fn download_state_path(temp_path: &Path) -> PathBuf {
+    let mut state_name = temp_path.as_os_str().to_os_string();
+    state_name.push(".state");
+    PathBuf::from(state_name)
+}
+
+fn start_download_state(state_path: &Path, e_tag: &str) -> Result<(), String> {
+    fs::write(state_path, format!("{}\n", e_tag)).map_err(|e| format!("Cannot write {:?}: {}", state_path, e))
+}
+
+fn append_download_range(state_path: &Path, start: u64, end: u64) -> std::io::Result<()> {
+    let mut file = fs::OpenOptions::new().append(true).open(state_path)?;
+    writeln!(file, "range={}-{}", start, end)
+}
+
+/// The ranges already written, or `None` when there is no partial download of this ETag.
+fn read_download_state(state_path: &Path, e_tag: &str) -> Option<Vec<(u64, u64)>> {
+    let content = fs::read_to_string(state_path).ok()?;
+    let mut lines = content.lines();
+    if lines.next()? != e_tag {
+        return None;
+    }
+    Some(lines
+        .filter_map(|line| line.strip_prefix("range="))
+        .filter_map(|range| range.split_once('-'))
+        .filter_map(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))
+        .collect())
+}
+
+async fn write_stream(mut body: ByteStream, file: &mut File, mut hasher: Option<&mut EtagHasher>) -> Result<(), String> {
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| format!("{:?}", e))?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&chunk);
         }
+        file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+    }
+    file.flush().await.map_err(|e| e.to_string())
+}
+
+/// Checks the downloaded content against the ETag, which for objects uploaded in parts
+/// is the MD5 of the part MD5s followed by the part count.
+fn verify_etag(key: &str, e_tag: &str, hasher: EtagHasher) -> Result<(), String> {
+    let computed = hasher.finish();
+    if computed != trim_etag(e_tag) {
+        return Err(format!("Downloaded {} does not match its ETag {}, computed {}", key, e_tag, computed));
+    }
+    Ok(())
+}
+
+/// Whether the content of the local file produces the ETag of the object with the given key,
+/// `None` if the ETag is not an MD5 due to the encryption of the object.
+pub(crate) async fn file_matches_etag(client_bucket: &ClientBucket, path: &Path, key: &str, e_tag: &str) -> Result<Option<bool>, String> {
+    let mut hasher = match etag_hasher(client_bucket, key, None, e_tag).await? {
+        Some(hasher) => hasher,
+        None => return Ok(None)
+    };
+    let length = path.metadata().map_err(|e| e.to_string())?.len();
+    hash_file(path, length, &mut hasher).await?;
+    Ok(Some(hasher.finish() == trim_etag(e_tag)))
+}
+
+/// A hasher for checking the content of the object against its ETag, read from the object itself.
+/// `None` if the ETag is not an MD5 due to the encryption of the object.
+async fn etag_hasher(client_bucket: &ClientBucket, key: &str, version_id: Option<&str>, e_tag: &str) -> Result<Option<EtagHasher>, String> {
+    let multipart = etag_part_count(e_tag).is_some();
+    let head = client_bucket.client
+        .head_object()
+        .bucket(&client_bucket.bucket_name)
+        .key(key)
+        .set_version_id(version_id.map(|v| v.to_string()))
+        .set_part_number(Some(1).filter(|_| multipart))
+        .send()
+        .await
+        .map_err(|e| format!("Cannot read the encryption of {} due to {:?}", key, e))?;
+    if !etag_is_md5(head.server_side_encryption(), head.sse_customer_algorithm()) {
+        return Ok(None);
     }
+    let part_size = Some(head.content_length() as u64).filter(|_| multipart);
+    Ok(Some(EtagHasher::new(part_size)))
 }
 
-async fn write_file(key: &str, output_printer: &dyn OutputPrinter, bytes: &mut AggregatedBytes, new_path: PathBuf) {
-    let mut file = File::create(new_path.clone()).await.unwrap();
-    let _ = file.write_all_buf(bytes).await;
-    file.flush().await.expect("Failed to flush downloaded message");
-    output_printer.ok_output(format!("Downloaded successfully {} to {:?}", key, new_path).as_str());
-}
\ No newline at end of file
+/// Size of the parts of an object uploaded in parts, `None` for an object uploaded in one go.
+async fn etag_part_size(client_bucket: &ClientBucket, key: &str, version_id: Option<&str>, e_tag: &str) -> Result<Option<u64>, String> {
+    if etag_part_count(e_tag).is_none() {
+        return Ok(None);
+    }
+    let head = client_bucket.client
+        .head_object()
+        .bucket(&client_bucket.bucket_name)
+        .key(key)
+        .set_version_id(version_id.map(|v| v.to_string()))
+        .part_number(1)
+        .send()
+        .await
+        .map_err(|e| format!("Cannot read the part size of {} due to {:?}", key, e))?;
+    Ok(Some(head.content_length() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_key_to_path_mirrors_key() {
+        let path = key_to_path(Path::new("target"), "a/b/file.txt", false, None);
+        assert_eq!(path, Some(Path::new("target").join("a").join("b").join("file.txt")));
+    }
+
+    #[test]
+    fn test_key_to_path_flatten() {
+        let path = key_to_path(Path::new("target"), "a/b/file.txt", true, None);
+        assert_eq!(path, Some(Path::new("target").join("file.txt")));
+    }
+
+    #[test]
+    fn test_key_to_path_strip_prefix() {
+        let path = key_to_path(Path::new("target"), "a/b/file.txt", false, Some("a/"));
+        assert_eq!(path, Some(Path::new("target").join("b").join("file.txt")));
+        let path = key_to_path(Path::new("target"), "c/file.txt", false, Some("a/"));
+        assert_eq!(path, Some(Path::new("target").join("c").join("file.txt")));
+    }
+
+    #[test]
+    fn test_key_to_path_rejects_parent_segments() {
+        assert_eq!(key_to_path(Path::new("target"), "a/../../file.txt", false, None), None);
+        assert_eq!(key_to_path(Path::new("target"), "a/", false, Some("a/")), None);
+        assert_eq!(key_to_path(Path::new("target"), "a/./file.txt", false, None), None);
+    }
+
+    #[test]
+    fn test_key_to_path_rejects_windows_paths() {
+        assert_eq!(key_to_path(Path::new("target"), "a/..\\..\\evil.txt", false, None), None);
+        assert_eq!(key_to_path(Path::new("target"), "a/\\Windows\\System32\\x.dll", false, None), None);
+        assert_eq!(key_to_path(Path::new("target"), "a/C:evil.txt", true, None), None);
+    }
+}
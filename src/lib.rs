@@ -2,20 +2,35 @@
 
 use date_utils::convert_date_time;
 use std::cell::RefCell;
+use std::sync::atomic::{AtomicU32, Ordering};
 use aws_sdk_s3::model::Object;
 use aws_sdk_s3::Client;
 use cli::Cli;
+use object_versions::VersionRecord;
 use std::env;
 
 mod date_utils;
 pub mod cli;
 pub mod client_factory;
 pub mod bucket_operations;
+pub mod file_delete;
+pub mod object_versions;
+pub mod object_copy;
+pub mod worker_pool;
 
 pub trait OutputPrinter {
     fn output_with_stats(&self, obj: &Object);
+    /// Prints a common prefix returned when listing with a delimiter. Printed as it is unless overridden.
+    fn output_directory(&self, prefix: &str) {
+        println!("{}", prefix);
+    }
+    fn output_version(&self, version: &VersionRecord);
     fn err_output(&self, msg: &str);
     fn ok_output(&self, msg: &str);
+    /// Prints progress and summary messages, which do not count as successes or errors.
+    fn info_output(&self, msg: &str) {
+        println!("{}", msg);
+    }
 }
 
 pub struct DefaultPrinter {
@@ -24,13 +39,36 @@ pub struct DefaultPrinter {
     pub error: RefCell<u32>
 }
 
+fn format_with_stats(obj: &Object, sep: &str) -> String {
+    let key_str = obj.key().unwrap();
+    let size = obj.size();
+    let last_modified = obj.last_modified();
+    let d = convert_date_time(last_modified);
+    format!("{}{}{:?}{}{} Kb", key_str, sep, d, sep, size / 1024)
+}
+
+fn format_version(version: &VersionRecord, sep: &str) -> String {
+    let status = if version.is_latest { "latest" } else { "noncurrent" };
+    let d = convert_date_time(version.last_modified.as_ref());
+    let size = if version.is_delete_marker {
+        "<DELETE MARKER>".to_string()
+    } else {
+        format!("{} Kb", version.size / 1024)
+    };
+    format!("{}{}{}{}{}{}{:?}{}{}", version.key, sep, version.version_id, sep, status, sep, d, sep, size)
+}
+
 impl OutputPrinter for DefaultPrinter {
     fn output_with_stats(&self, obj: &Object) {
-        let key_str = obj.key().unwrap();
-        let size = obj.size();
-        let last_modified = obj.last_modified();
-        let d = convert_date_time(last_modified);
-        println!("{}{}{:?}{}{} Kb", key_str, self.sep, d, self.sep, size / 1024);
+        println!("{}", format_with_stats(obj, &self.sep));
+    }
+
+    fn output_directory(&self, prefix: &str) {
+        println!("{}{}<DIR>", prefix, self.sep);
+    }
+
+    fn output_version(&self, version: &VersionRecord) {
+        println!("{}", format_version(version, &self.sep));
     }
 
     fn err_output(&self, msg: &str) {
@@ -44,6 +82,47 @@ impl OutputPrinter for DefaultPrinter {
     }
 }
 
+/// Thread-safe counterpart of `DefaultPrinter`, used when objects are processed concurrently.
+pub struct SyncPrinter {
+    pub sep: String,
+    pub success: AtomicU32,
+    pub error: AtomicU32
+}
+
+impl SyncPrinter {
+    pub fn new(sep: String) -> SyncPrinter {
+        SyncPrinter {
+            sep,
+            success: AtomicU32::new(0),
+            error: AtomicU32::new(0),
+        }
+    }
+}
+
+impl OutputPrinter for SyncPrinter {
+    fn output_with_stats(&self, obj: &Object) {
+        println!("{}", format_with_stats(obj, &self.sep));
+    }
+
+    fn output_directory(&self, prefix: &str) {
+        println!("{}{}<DIR>", prefix, self.sep);
+    }
+
+    fn output_version(&self, version: &VersionRecord) {
+        println!("{}", format_version(version, &self.sep));
+    }
+
+    fn err_output(&self, msg: &str) {
+        eprintln!("{}", msg);
+        self.error.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn ok_output(&self, msg: &str) {
+        println!("{}", msg);
+        self.success.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
 pub struct ClientBucket {
     pub client: Client,
     pub bucket_name: String,
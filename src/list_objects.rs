@@ -1,15 +1,17 @@
+use std::cmp::Reverse;
 use std::future::Future;
 
 use aws_sdk_s3::Error;
-use aws_sdk_s3::Error::Unhandled;
 use aws_sdk_s3::model::Object;
 use fancy_regex::Regex;
 
 use aws_client::{ClientBucket, OutputPrinter};
+use aws_client::object_versions::{VersionPages, VersionRecord};
+use aws_client::worker_pool::WorkerPool;
 
 use crate::ResultSorter;
 
-fn find_regex(content: &str, search_filter: &Regex) -> i32 {
+pub(crate) fn find_regex(content: &str, search_filter: &Regex) -> i32 {
     let result = search_filter.find(content);
     if result.is_ok() {
         let match_option = result.unwrap();
@@ -23,6 +25,152 @@ fn find_regex(content: &str, search_filter: &Regex) -> i32 {
     return -1;
 }
 
+/// Pages through `list_objects_v2`, handing out the objects matching the list regex one page at a time.
+/// The common prefixes matching the list regex are collected until they are taken.
+pub(crate) struct ObjectPages<'a> {
+    client_bucket: &'a ClientBucket,
+    re: Regex,
+    sorter: Option<ResultSorter>,
+    common_prefixes: Vec<String>,
+    continuation_token: Option<String>,
+    done: bool,
+    pages: u32,
+    keys_scanned: u64,
+}
+
+impl<'a> ObjectPages<'a> {
+    pub(crate) fn new(client_bucket: &'a ClientBucket) -> ObjectPages<'a> {
+        ObjectPages {
+            client_bucket,
+            re: extract_list_regex_pattern(&client_bucket),
+            sorter: None,
+            common_prefixes: Vec::new(),
+            continuation_token: None,
+            done: false,
+            pages: 0,
+            keys_scanned: 0,
+        }
+    }
+
+    /// Like `new`, but with `--asc` all objects are handed out as a single page sorted by date.
+    pub(crate) fn sorted(client_bucket: &'a ClientBucket) -> ObjectPages<'a> {
+        ObjectPages {
+            sorter: create_result_sorter(client_bucket),
+            ..ObjectPages::new(client_bucket)
+        }
+    }
+
+    pub(crate) async fn next_page(&mut self) -> Result<Option<Vec<Object>>, Error> {
+        match self.sorter.take() {
+            // Sorting needs the full result, so only buffer when it was asked for
+            Some(mut sorter) => {
+                while let Some(page) = self.fetch_page().await? {
+                    page.into_iter().for_each(|obj| sorter.sort_results(obj));
+                }
+                Ok(Some(sorter.get_sorted()))
+            }
+            None => self.fetch_page().await
+        }
+    }
+
+    /// The common prefixes of the pages handed out so far, which are only returned when listing with a delimiter.
+    pub(crate) fn take_common_prefixes(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.common_prefixes)
+    }
+
+    async fn fetch_page(&mut self) -> Result<Option<Vec<Object>>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        let args = &self.client_bucket.args;
+        let objects = self.client_bucket.client.list_objects_v2()
+            .bucket(&self.client_bucket.bucket_name)
+            .set_prefix(args.prefix.clone())
+            .set_delimiter(args.delimiter.clone())
+            .set_continuation_token(self.continuation_token.take())
+            .send()
+            .await?;
+        self.pages += 1;
+        for common_prefix in objects.common_prefixes().unwrap_or_default() {
+            let prefix = common_prefix.prefix().unwrap_or_default();
+            if find_regex(prefix, &self.re) > -1 {
+                self.common_prefixes.push(prefix.to_string());
+            }
+        }
+        self.continuation_token = objects.next_continuation_token().map(|t| t.to_string());
+        self.done = !objects.is_truncated() || self.continuation_token.is_none();
+        let mut matches = Vec::new();
+        for obj in objects.contents.unwrap_or_default() {
+            self.keys_scanned += 1;
+            if find_regex(obj.key().unwrap(), &self.re) > -1 {
+                matches.push(obj);
+            }
+        }
+        Ok(Some(matches))
+    }
+
+    pub(crate) fn print_stats(&self, output_printer: &dyn OutputPrinter) {
+        output_printer.info_output(format!("Scanned {} keys in {} pages", self.keys_scanned, self.pages).as_str());
+    }
+}
+
+/// Regroups the pages of an `ObjectPages` into batches of up to `batch_size` objects.
+pub(crate) struct ObjectBatches<'a> {
+    object_pages: ObjectPages<'a>,
+    batch_size: usize,
+    pending: Vec<Object>,
+}
+
+impl<'a> ObjectBatches<'a> {
+    pub(crate) fn new(object_pages: ObjectPages<'a>, batch_size: usize) -> ObjectBatches<'a> {
+        ObjectBatches {
+            object_pages,
+            batch_size: batch_size.max(1),
+            pending: Vec::new(),
+        }
+    }
+
+    pub(crate) async fn next_batch(&mut self) -> Result<Option<Vec<Object>>, Error> {
+        while self.pending.len() < self.batch_size {
+            match self.object_pages.next_page().await? {
+                Some(page) => self.pending.extend(page),
+                None => break
+            }
+        }
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.pending.split_off(self.pending.len().min(self.batch_size));
+        Ok(Some(std::mem::replace(&mut self.pending, rest)))
+    }
+
+    pub(crate) fn print_stats(&self, output_printer: &dyn OutputPrinter) {
+        self.object_pages.print_stats(output_printer);
+    }
+}
+
+fn create_result_sorter(client_bucket: &ClientBucket) -> Option<ResultSorter> {
+    match &client_bucket.args.asc {
+        Some(asc_bool) => {
+            Some(ResultSorter { results: Vec::new(), asc: if *asc_bool { 1 } else { -1 } })
+        }
+        None => None
+    }
+}
+
+/// Prints the matching objects and, when listing with a delimiter, the matching common prefixes as directories.
+pub(crate) async fn list_bucket(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), Error> {
+    let mut object_pages = ObjectPages::sorted(client_bucket);
+    while let Some(page) = object_pages.next_page().await? {
+        for prefix in object_pages.take_common_prefixes() {
+            output_printer.output_directory(prefix.as_str());
+        }
+        page.iter().for_each(|obj| output_printer.output_with_stats(obj));
+    }
+    object_pages.print_stats(output_printer);
+    Ok(())
+}
+
 pub(crate) async fn list_objects<'a, F, Fut>(client_bucket: &'a ClientBucket,
                                              output_printer: &'a dyn OutputPrinter,
                                              process_obj: F) -> Result<(), Error>
@@ -30,32 +178,40 @@ pub(crate) async fn list_objects<'a, F, Fut>(client_bucket: &'a ClientBucket,
         F: FnOnce(&'a ClientBucket, Object, &'a dyn OutputPrinter) -> Fut + std::marker::Copy,
         Fut: Future<Output=()>
 {
-    let client = &client_bucket.client;
-    let bucket_name = &client_bucket.bucket_name;
-    let objects = client.list_objects_v2().bucket(bucket_name).send().await?;
-    let re = extract_list_regex_pattern(&client_bucket);
-    let asc = match &client_bucket.args.asc {
-        Some(asc_bool) => {
-            if *asc_bool { 1 } else { -1 }
-        }
-        None => 1
-    };
-    let mut result_sorter = ResultSorter { results: Vec::new(), asc };
-    for obj in objects.contents().unwrap_or_default() {
-        let key_str = obj.key().unwrap();
-        if find_regex(key_str, &re) > -1 {
-            result_sorter.sort_results(obj.clone());
+    let mut worker_pool = WorkerPool::new(client_bucket.args.concurrency);
+    let mut object_pages = ObjectPages::sorted(client_bucket);
+    while let Some(page) = worker_pool.run_alongside(object_pages.next_page()).await? {
+        for obj in page {
+            worker_pool.submit(process_obj(client_bucket, obj, output_printer)).await;
         }
     }
+    while worker_pool.next().await.is_some() {}
+    object_pages.print_stats(output_printer);
+
+    Ok(())
+}
 
-    for obj in result_sorter.get_sorted().iter() {
-        process_obj(client_bucket, obj.clone(), output_printer).await;
+/// Like `list_objects`, but hands the matching objects to `process_batch` in batches of up to `batch_size`.
+pub(crate) async fn list_object_batches<'a, F, Fut>(client_bucket: &'a ClientBucket,
+                                                    output_printer: &'a dyn OutputPrinter,
+                                                    batch_size: usize,
+                                                    process_batch: F) -> Result<(), Error>
+    where
+        F: FnOnce(&'a ClientBucket, Vec<Object>, &'a dyn OutputPrinter) -> Fut + std::marker::Copy,
+        Fut: Future<Output=()>
+{
+    let mut worker_pool = WorkerPool::new(client_bucket.args.concurrency);
+    let mut object_batches = ObjectBatches::new(ObjectPages::sorted(client_bucket), batch_size);
+    while let Some(batch) = worker_pool.run_alongside(object_batches.next_batch()).await? {
+        worker_pool.submit(process_batch(client_bucket, batch, output_printer)).await;
     }
+    while worker_pool.next().await.is_some() {}
+    object_batches.print_stats(output_printer);
 
     Ok(())
 }
 
-fn extract_list_regex_pattern(client_bucket: &&ClientBucket) -> Regex {
+pub(crate) fn extract_list_regex_pattern(client_bucket: &&ClientBucket) -> Regex {
     let regex = match &client_bucket.args.list_regex_pattern {
         Some(re) => {
             re
@@ -68,26 +224,32 @@ fn extract_list_regex_pattern(client_bucket: &&ClientBucket) -> Regex {
     return re.clone()
 }
 
+/// Prints every version and delete marker, page by page or, with `--asc`, sorted by last modified date,
+/// and the matching common prefixes as directories.
 pub(crate) async fn list_object_versions(client_bucket: &ClientBucket,
-                                         output_printer: &dyn OutputPrinter) -> Result<(), Error> {
-    let client = &client_bucket.client;
-    let bucket = &client_bucket.bucket_name;
-    let re = extract_list_regex_pattern(&client_bucket);
-    let res = client.list_object_versions().bucket(bucket.as_str()).send().await;
-    match res {
-        Ok(list) => {
-            for version in list.versions().unwrap_or_default() {
-                let key_str = version.key().unwrap_or_default();
-                if find_regex(key_str, &re) > -1 {
-                    output_printer.ok_output(format!("{} :: version ID: {}",
-                                                     key_str,
-                                                     version.version_id().unwrap_or_default()).as_str())
-                }
-            }
-            return Ok(());
+                                         output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let asc = client_bucket.args.asc;
+    let mut sorted: Vec<VersionRecord> = Vec::new();
+    let mut version_pages = VersionPages::new(client_bucket)?;
+    while let Some(page) = version_pages.next_page().await
+        .map_err(|e| format!("Could not list bucket versions: {:?}", e))? {
+        for prefix in version_pages.take_common_prefixes() {
+            output_printer.output_directory(prefix.as_str());
         }
-        Err(e) => {
-            return Err(Unhandled(e.into()));
+        if asc.is_some() {
+            sorted.extend(page);
+        } else {
+            page.iter().for_each(|version| output_printer.output_version(version));
         }
     }
-}
\ No newline at end of file
+    if let Some(asc) = asc {
+        if asc {
+            sorted.sort_by_key(|version| version.last_modified_secs());
+        } else {
+            sorted.sort_by_key(|version| Reverse(version.last_modified_secs()));
+        }
+        sorted.iter().for_each(|version| output_printer.output_version(version));
+    }
+    version_pages.print_stats(output_printer);
+    Ok(())
+}
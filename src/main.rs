@@ -2,41 +2,54 @@ extern crate alloc;
 extern crate core;
 extern crate glob;
 
-use std::cell::RefCell;
 use std::path::Path;
 
-use aws_sdk_s3::{Client, Error};
+use aws_sdk_s3::Client;
 use aws_sdk_s3::model::Object;
 use aws_smithy_http::byte_stream::ByteStream;
 use clap::Parser;
+use simple_error::SimpleError;
 
-use aws_client::{check_print_env_variables, DefaultPrinter, OutputPrinter};
+use aws_client::{check_print_env_variables, OutputPrinter, SyncPrinter};
 use aws_client::cli::Cli;
 use aws_client::cli::Operation;
 use aws_client::ClientBucket;
+use aws_client::bucket_operations::{copy_to_bucket, create_bucket, delete_bucket, force_delete_bucket, list_buckets};
+use aws_client::file_delete::{delete_object, delete_objects_batch, MAX_DELETE_BATCH};
+use aws_client::object_copy::check_copy_options;
 use Operation::{CopyBucketToBucket, CopyMultiple, CopySingle, CreateBucket, Delete, DeleteBucket, Download, List,
-                ListBuckets, MoveMultiple, MoveSingle, Upload, ListObjectVersions, UploadLarge};
+                ListBuckets, MoveMultiple, MoveSingle, Upload, ListObjectVersions, UploadLarge, Sync, SyncBucketToBucket,
+                RestoreToTime, Undelete, PruneVersions};
 
-use crate::bucket_operations::{copy_to_bucket, create_bucket, delete_bucket, list_buckets};
+use crate::checksum::{etag_is_md5, file_md5, md5_base64, md5_hex, trim_etag};
 use crate::client_factory::setup;
-use crate::copy_operations::{copy_multiple_process_obj, copy_object, move_multiple_process_obj, move_object};
-use crate::file_delete::delete_object;
+use crate::copy_operations::{copy_multiple_process_obj, copy_object, move_multiple_process_obj, move_object,
+                             rename_multiple, sync_bucket_process_obj};
 use crate::file_download::download_object;
-use crate::list_objects::{list_object_versions, list_objects};
+use crate::list_objects::{list_bucket, list_object_batches, list_object_versions, list_objects};
 use crate::result_sorter::ResultSorter;
+use crate::sync_operations::sync_operation;
 use crate::upload_files::{upload_file_in_chunks, upload_files_operation};
+use crate::version_operations::{copy_versions, delete_versions, download_versions, undelete};
+use crate::version_prune::prune_versions;
+use crate::version_restore::restore_to_time;
 
 mod cli;
 mod output_printer;
 mod result_sorter;
 mod file_download;
-mod file_delete;
 mod list_objects;
 mod copy_operations;
+mod rename_template;
+mod transfer_policy;
+mod sync_operations;
+mod version_restore;
+mod version_operations;
+mod version_prune;
 mod upload_files;
-mod bucket_operations;
-mod date_utils;
+mod upload_state;
 mod client_factory;
+mod checksum;
 
 #[tokio::main]
 async fn main() {
@@ -51,11 +64,7 @@ async fn main() {
         Some(s) => s,
         None => &default_sep
     };
-    let output_printer = DefaultPrinter {
-        sep: sep.to_string(),
-        success: RefCell::new(0),
-        error: RefCell::new(0),
-    };
+    let output_printer = SyncPrinter::new(sep.to_string());
 
     check_print_env_variables(&output_printer);
 
@@ -66,20 +75,21 @@ async fn main() {
         output_printer.ok_output("");
 
         let client_bucket = &ClientBucket::new(client, bucket, args.clone());
+        if let Err(e) = check_copy_options(&args) {
+            output_printer.err_output(e.as_str());
+            return;
+        }
         match mode {
             List => {
-                async fn process_obj(_: &ClientBucket, obj: Object, output_printer: &dyn OutputPrinter) {
-                    output_printer.output_with_stats(&obj);
-                }
-                let res = list_objects(client_bucket, &output_printer, process_obj).await;
+                let res = list_bucket(client_bucket, &output_printer).await;
                 if res.is_err() {
                     output_printer.err_output(format!("Could not list bucket: {:?}", res.err().unwrap()).as_str());
                 }
             }
             ListObjectVersions => {
                 let res = list_object_versions(client_bucket, &output_printer).await;
-                if res.is_err() {
-                    output_printer.err_output(format!("Could not list bucket versions: {:?}", res.err().unwrap()).as_str());
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
                 }
             }
             Upload => {
@@ -93,25 +103,39 @@ async fn main() {
                     }
                 }
             }
+            Download if args.version_id.is_some() || args.versions_file.is_some() => {
+                let res = download_versions(client_bucket, &output_printer).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
+                }
+            }
             Download => {
                 async fn process_obj(client_bucket: &ClientBucket,
                                      obj: Object,
                                      output_printer: &dyn OutputPrinter) {
-                    download_object(client_bucket, obj.key().unwrap(), output_printer).await
+                    download_object(client_bucket, &obj, output_printer).await
                 }
                 let _ = list_objects(client_bucket,
                                      &output_printer,
                                      process_obj).await;
             }
+            Delete if args.version_id.is_some() || args.versions_file.is_some() => {
+                let res = delete_versions(client_bucket, &output_printer).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
+                }
+            }
             Delete => {
-                async fn process_obj(client_bucket: &ClientBucket,
-                                     obj: Object,
-                                     output_printer: &dyn OutputPrinter) {
-                    delete_object(client_bucket, obj.key().unwrap(), output_printer).await
+                let _ = list_object_batches(client_bucket,
+                                            &output_printer,
+                                            MAX_DELETE_BATCH,
+                                            delete_objects_batch).await;
+            }
+            CopyMultiple | MoveMultiple if args.rename_template.is_some() => {
+                let res = rename_multiple(client_bucket, &output_printer, matches!(mode, MoveMultiple)).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
                 }
-                let _ = list_objects(client_bucket,
-                                     &output_printer,
-                                     process_obj).await;
             }
             CopyMultiple => {
                 let _ = list_objects(client_bucket,
@@ -123,6 +147,12 @@ async fn main() {
                                      &output_printer,
                                      move_multiple_process_obj).await;
             }
+            CopySingle | MoveSingle if args.versions_file.is_some() => {
+                let res = copy_versions(client_bucket, &output_printer, matches!(mode, MoveSingle)).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
+                }
+            }
             CopySingle => {
                 let _ = copy_object(client_bucket, &output_printer).await;
             }
@@ -132,6 +162,12 @@ async fn main() {
             CreateBucket => {
                 let _ = create_bucket(client_bucket, &output_printer).await;
             }
+            DeleteBucket if args.force => {
+                let res = force_delete_bucket(client_bucket, &output_printer).await;
+                if let Err(e) = res {
+                    output_printer.err_output(format!("Could not empty bucket: {:?}", e).as_str());
+                }
+            }
             DeleteBucket => {
                 let _ = delete_bucket(client_bucket, &output_printer).await;
             }
@@ -148,6 +184,42 @@ async fn main() {
                     }
                 }
             }
+            Sync => {
+                let res = sync_operation(client_bucket, &output_printer).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
+                }
+            }
+            SyncBucketToBucket => {
+                if args.target_bucket.is_none() {
+                    output_printer.err_output("Target bucket is missing. Please specify the target bucket.");
+                    return;
+                }
+                let res = list_objects(client_bucket,
+                                       &output_printer,
+                                       sync_bucket_process_obj).await;
+                if res.is_err() {
+                    output_printer.err_output(format!("Could not list bucket: {:?}", res.err().unwrap()).as_str());
+                }
+            }
+            RestoreToTime => {
+                let res = restore_to_time(client_bucket, &output_printer).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
+                }
+            }
+            Undelete => {
+                let res = undelete(client_bucket, &output_printer).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
+                }
+            }
+            PruneVersions => {
+                let res = prune_versions(client_bucket, &output_printer).await;
+                if let Err(e) = res {
+                    output_printer.err_output(e.as_str());
+                }
+            }
             _ => {}
         }
     } else {
@@ -168,15 +240,28 @@ pub async fn upload_object(
     bucket_name: &str,
     file_name: &str,
     key: &str,
-) -> Result<(), Error> {
-    let body = ByteStream::from_path(Path::new(file_name)).await;
-    client
+) -> Result<(), SimpleError> {
+    let path = Path::new(file_name);
+    let file_size = path.metadata()
+        .map_err(|e| SimpleError::new(format!("Cannot read {}: {}", file_name, e)))?.len();
+    let digest = file_md5(path, 0, file_size).await
+        .map_err(|e| SimpleError::new(format!("Cannot read {}: {}", file_name, e)))?;
+    let md5 = md5_hex(&digest);
+    let body = ByteStream::from_path(path).await
+        .map_err(|e| SimpleError::new(format!("Cannot read {}: {:?}", file_name, e)))?;
+    let output = client
         .put_object()
         .bucket(bucket_name)
         .key(key)
-        .body(body.unwrap())
+        .content_md5(md5_base64(&digest))
+        .body(body)
         .send()
-        .await?;
+        .await
+        .map_err(|e| SimpleError::new(format!("{:?}", e)))?;
+    if etag_is_md5(output.server_side_encryption(), output.sse_customer_algorithm()) && trim_etag(output.e_tag().unwrap_or_default()) != md5 {
+        return Err(SimpleError::new(format!("ETag {} of {} does not match the MD5 {} of {}",
+                                            output.e_tag().unwrap_or_default(), key, md5, file_name)));
+    }
 
     println!("Uploaded file: {}", file_name);
     Ok(())
@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+
+use aws_sdk_s3::Error;
+use aws_sdk_s3::Error::Unhandled;
+use aws_sdk_s3::model::{AccessControlPolicy, CompletedMultipartUpload, CompletedPart, MetadataDirective,
+                        ObjectCannedAcl, StorageClass, TaggingDirective};
+use aws_sdk_s3::output::HeadObjectOutput;
+
+use crate::ClientBucket;
+use crate::cli::{Cli, CopyDirective, Operation};
+use crate::worker_pool::WorkerPool;
+
+const MIB: i64 = 1024 * 1024;
+/// Largest source accepted by a single `CopyObject` request.
+const MAX_SINGLE_COPY_SIZE: i64 = 5 * 1024 * MIB;
+const MIN_COPY_PART_SIZE: i64 = 512 * MIB;
+const MAX_COPY_PARTS: i64 = 10000;
+const PRESERVE_ACL: &str = "preserve";
+/// User metadata in which sync-bucket-to-bucket records the ETag of the source of a copy, as the
+/// ETag of the copy differs from it when the source or the copy was uploaded in parts.
+pub const SOURCE_ETAG_METADATA: &str = "source-etag";
+
+/// What a copy takes over from the source, from the command line options.
+/// `None` for the metadata or tagging means the ones of the source are kept.
+struct CopyOptions {
+    metadata: Option<HashMap<String, String>>,
+    tagging: Option<String>,
+    storage_class: Option<StorageClass>,
+    acl: Option<ObjectCannedAcl>,
+    preserve_acl: bool,
+    record_source_e_tag: bool,
+}
+
+impl CopyOptions {
+    fn from_args(args: &Cli) -> Result<CopyOptions, String> {
+        if !args.metadata.is_empty() && args.metadata_directive != Some(CopyDirective::Replace) {
+            return Err("--metadata is only applied with --metadata-directive replace".to_string());
+        }
+        if !args.tag.is_empty() && args.tagging_directive != Some(CopyDirective::Replace) {
+            return Err("--tag is only applied with --tagging-directive replace".to_string());
+        }
+        let metadata = match args.metadata_directive {
+            Some(CopyDirective::Replace) => Some(parse_pairs(&args.metadata)?.into_iter().collect()),
+            _ => None
+        };
+        let tagging = match args.tagging_directive {
+            Some(CopyDirective::Replace) => Some(encode_tagging(&parse_pairs(&args.tag)?)),
+            _ => None
+        };
+        let acl = args.acl.as_deref().filter(|acl| *acl != PRESERVE_ACL);
+        Ok(CopyOptions {
+            metadata,
+            tagging,
+            storage_class: args.storage_class.as_deref().map(StorageClass::from),
+            acl: acl.map(ObjectCannedAcl::from),
+            preserve_acl: args.acl.as_deref() == Some(PRESERVE_ACL),
+            record_source_e_tag: matches!(args.mode, Operation::SyncBucketToBucket),
+        })
+    }
+}
+
+/// Checks the copy options on the command line, so that a mistake is reported once before
+/// anything is copied rather than for every object.
+pub fn check_copy_options(args: &Cli) -> Result<(), String> {
+    CopyOptions::from_args(args).map(|_| ())
+}
+
+/// Copies an object, possibly between buckets. Sources larger than 5 GB, which S3 rejects for
+/// a single copy request, are copied in byte ranges with `--concurrency` parts in parallel.
+/// The copy keeps the storage class of the source unless `--storage-class` is given, and the
+/// server side encryption of the source unless that uses a customer provided key.
+/// Without a version id the current version of the source is copied.
+pub async fn copy_object_any_size(client_bucket: &ClientBucket,
+                                         source_bucket: &str,
+                                         source_key: &str,
+                                         source_version_id: Option<&str>,
+                                         target_bucket: &str,
+                                         target_key: &str) -> Result<(), Error> {
+    let options = CopyOptions::from_args(&client_bucket.args).map_err(|e| Unhandled(e.into()))?;
+    let copy = ObjectCopy {
+        client_bucket,
+        source_bucket,
+        source_key,
+        source_version_id,
+        target_bucket,
+        target_key,
+        options: &options,
+    };
+    let head = client_bucket.client
+        .head_object()
+        .bucket(source_bucket)
+        .key(source_key)
+        .set_version_id(source_version_id.map(|v| v.to_string()))
+        .send()
+        .await?;
+    if head.content_length() <= MAX_SINGLE_COPY_SIZE {
+        copy.copy_single(&head).await?;
+    } else {
+        copy.copy_multipart(&head).await?;
+    }
+    if options.preserve_acl {
+        copy.copy_acl().await?;
+    }
+    Ok(())
+}
+
+struct ObjectCopy<'a> {
+    client_bucket: &'a ClientBucket,
+    source_bucket: &'a str,
+    source_key: &'a str,
+    source_version_id: Option<&'a str>,
+    target_bucket: &'a str,
+    target_key: &'a str,
+    options: &'a CopyOptions,
+}
+
+impl<'a> ObjectCopy<'a> {
+    fn copy_source(&self) -> String {
+        match self.source_version_id {
+            Some(version_id) => format!("{}/{}?versionId={}", self.source_bucket, self.source_key, version_id),
+            None => format!("{}/{}", self.source_bucket, self.source_key)
+        }
+    }
+
+    /// The user metadata of the copy, `None` when a single copy request can take it over from the source.
+    fn metadata(&self, head: &HeadObjectOutput) -> Option<HashMap<String, String>> {
+        if !self.options.record_source_e_tag {
+            return self.options.metadata.clone();
+        }
+        let mut metadata = self.options.metadata.clone()
+            .or_else(|| head.metadata().cloned())
+            .unwrap_or_default();
+        let source_e_tag = head.e_tag().unwrap_or_default().trim_matches('"');
+        metadata.insert(SOURCE_ETAG_METADATA.to_string(), source_e_tag.to_string());
+        Some(metadata)
+    }
+
+    fn storage_class(&self, head: &HeadObjectOutput) -> Option<StorageClass> {
+        self.options.storage_class.clone().or_else(|| head.storage_class().cloned())
+    }
+
+    async fn copy_single(&self, head: &HeadObjectOutput) -> Result<(), Error> {
+        let mut request = self.client_bucket.client
+            .copy_object()
+            .copy_source(self.copy_source())
+            .bucket(self.target_bucket)
+            .key(self.target_key)
+            .set_storage_class(self.storage_class(head))
+            .set_server_side_encryption(head.server_side_encryption().cloned())
+            .set_ssekms_key_id(head.ssekms_key_id().map(|s| s.to_string()))
+            .set_bucket_key_enabled(Some(head.bucket_key_enabled()).filter(|enabled| *enabled))
+            .set_acl(self.options.acl.clone());
+        if let Some(metadata) = self.metadata(head) {
+            // Replacing the metadata also replaces the content headers, which are kept as they are
+            request = request
+                .metadata_directive(MetadataDirective::Replace)
+                .set_metadata(Some(metadata))
+                .set_content_type(head.content_type().map(|s| s.to_string()))
+                .set_content_encoding(head.content_encoding().map(|s| s.to_string()))
+                .set_content_disposition(head.content_disposition().map(|s| s.to_string()))
+                .set_content_language(head.content_language().map(|s| s.to_string()))
+                .set_cache_control(head.cache_control().map(|s| s.to_string()));
+        }
+        if let Some(tagging) = &self.options.tagging {
+            request = request
+                .tagging_directive(TaggingDirective::Replace)
+                .tagging(tagging);
+        }
+        request.send().await?;
+        Ok(())
+    }
+
+    async fn copy_multipart(&self, head: &HeadObjectOutput) -> Result<(), Error> {
+        let client = &self.client_bucket.client;
+        // Unlike a single copy request, a multipart upload does not take over anything from the source
+        let metadata = self.metadata(head).or_else(|| head.metadata().cloned());
+        let tagging = match &self.options.tagging {
+            Some(tagging) => tagging.clone(),
+            None => self.source_tagging().await?,
+        };
+        let upload = client
+            .create_multipart_upload()
+            .bucket(self.target_bucket)
+            .key(self.target_key)
+            .set_content_type(head.content_type().map(|s| s.to_string()))
+            .set_content_encoding(head.content_encoding().map(|s| s.to_string()))
+            .set_content_disposition(head.content_disposition().map(|s| s.to_string()))
+            .set_content_language(head.content_language().map(|s| s.to_string()))
+            .set_cache_control(head.cache_control().map(|s| s.to_string()))
+            .set_metadata(metadata)
+            .set_tagging(Some(tagging).filter(|t| !t.is_empty()))
+            .set_storage_class(self.storage_class(head))
+            .set_server_side_encryption(head.server_side_encryption().cloned())
+            .set_ssekms_key_id(head.ssekms_key_id().map(|s| s.to_string()))
+            .set_bucket_key_enabled(Some(head.bucket_key_enabled()).filter(|enabled| *enabled))
+            .set_acl(self.options.acl.clone())
+            .send()
+            .await?;
+        let upload_id = upload.upload_id()
+            .ok_or_else(|| Unhandled(format!("No upload id to copy {}", self.copy_source()).into()))?;
+        let res = self.copy_parts(head, upload_id).await;
+        match res {
+            Ok(parts) => {
+                client
+                    .complete_multipart_upload()
+                    .bucket(self.target_bucket)
+                    .key(self.target_key)
+                    .upload_id(upload_id)
+                    .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                    .send()
+                    .await?;
+                Ok(())
+            }
+            Err(e) => {
+                // Parts of an aborted upload are not billed for storage
+                let _ = client
+                    .abort_multipart_upload()
+                    .bucket(self.target_bucket)
+                    .key(self.target_key)
+                    .upload_id(upload_id)
+                    .send()
+                    .await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn copy_parts(&self, head: &HeadObjectOutput, upload_id: &str) -> Result<Vec<CompletedPart>, Error> {
+        let size = head.content_length();
+        let part_size = copy_part_size(size);
+        let source_e_tag = head.e_tag().unwrap_or_default();
+        let mut worker_pool = WorkerPool::new(self.client_bucket.args.concurrency);
+        let mut parts = Vec::new();
+        let mut part_number = 1;
+        let mut start = 0;
+        while start < size {
+            let end = (start + part_size).min(size) - 1;
+            let part = self.copy_part(upload_id, source_e_tag, part_number, start, end);
+            if let Some(part) = worker_pool.submit(part).await {
+                parts.push(part?);
+            }
+            part_number += 1;
+            start = end + 1;
+        }
+        while let Some(part) = worker_pool.next().await {
+            parts.push(part?);
+        }
+        parts.sort_by_key(|part| part.part_number());
+        Ok(parts)
+    }
+
+    async fn copy_part(&self, upload_id: &str, source_e_tag: &str, part_number: i32, start: i64, end: i64) -> Result<CompletedPart, Error> {
+        let output = self.client_bucket.client
+            .upload_part_copy()
+            .bucket(self.target_bucket)
+            .key(self.target_key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .copy_source(self.copy_source())
+            .copy_source_range(format!("bytes={}-{}", start, end))
+            // Fails the copy if the source is replaced while its parts are copied
+            .copy_source_if_match(source_e_tag)
+            .send()
+            .await?;
+        Ok(CompletedPart::builder()
+            .set_e_tag(output.copy_part_result().and_then(|r| r.e_tag()).map(|e| e.to_string()))
+            .part_number(part_number)
+            .build())
+    }
+
+    async fn source_tagging(&self) -> Result<String, Error> {
+        let output = self.client_bucket.client
+            .get_object_tagging()
+            .bucket(self.source_bucket)
+            .key(self.source_key)
+            .set_version_id(self.source_version_id.map(|v| v.to_string()))
+            .send()
+            .await?;
+        let tags: Vec<(String, String)> = output.tag_set().unwrap_or_default().iter()
+            .map(|tag| (tag.key().unwrap_or_default().to_string(), tag.value().unwrap_or_default().to_string()))
+            .collect();
+        Ok(encode_tagging(&tags))
+    }
+
+    async fn copy_acl(&self) -> Result<(), Error> {
+        let client = &self.client_bucket.client;
+        let acl = client
+            .get_object_acl()
+            .bucket(self.source_bucket)
+            .key(self.source_key)
+            .set_version_id(self.source_version_id.map(|v| v.to_string()))
+            .send()
+            .await?;
+        let policy = AccessControlPolicy::builder()
+            .set_grants(acl.grants().map(|grants| grants.to_vec()))
+            .set_owner(acl.owner().cloned())
+            .build();
+        client
+            .put_object_acl()
+            .bucket(self.target_bucket)
+            .key(self.target_key)
+            .access_control_policy(policy)
+            .send()
+            .await?;
+        Ok(())
+    }
+}
+
+/// Size of the copied byte ranges, large enough to stay below the maximum number of parts.
+fn copy_part_size(size: i64) -> i64 {
+    let min_for_count = (size + MAX_COPY_PARTS - 1) / MAX_COPY_PARTS;
+    let rounded_to_mib = (min_for_count + MIB - 1) / MIB * MIB;
+    rounded_to_mib.max(MIN_COPY_PART_SIZE)
+}
+
+fn parse_pairs(pairs: &[String]) -> Result<Vec<(String, String)>, String> {
+    pairs.iter()
+        .map(|pair| pair.split_once('=')
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .ok_or_else(|| format!("Expected key=value instead of {}", pair)))
+        .collect()
+}
+
+/// Tags in the URL query form expected by the tagging header.
+fn encode_tagging(tags: &[(String, String)]) -> String {
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", encode_query_value(key), encode_query_value(value)))
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+fn encode_query_value(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_copy_part_size() {
+        assert_eq!(copy_part_size(6 * 1024 * MIB), MIN_COPY_PART_SIZE);
+        let size = 5 * 1024 * 1024 * MIB;
+        let part_size = copy_part_size(size);
+        assert_eq!(part_size % MIB, 0);
+        assert!((size + part_size - 1) / part_size <= MAX_COPY_PARTS);
+    }
+
+    #[test]
+    fn test_check_copy_options() {
+        let parse = |args: &[&str]| Cli::parse_from([&["aws_client", "--region", "eu-west-2", "--mode", "copy-single"][..], args].concat());
+        assert!(check_copy_options(&parse(&["--metadata-directive", "replace", "--metadata", "team=data"])).is_ok());
+        assert!(check_copy_options(&parse(&["--metadata", "team=data"])).is_err());
+        assert!(check_copy_options(&parse(&["--tagging-directive", "copy", "--tag", "stage=prod"])).is_err());
+        assert!(check_copy_options(&parse(&["--metadata-directive", "replace", "--metadata", "team"])).is_err());
+    }
+
+    #[test]
+    fn test_encode_tagging() {
+        let tags = parse_pairs(&["team=data ops".to_string(), "stage=prod&dev".to_string()]).unwrap();
+        assert_eq!(encode_tagging(&tags), "team=data%20ops&stage=prod%26dev");
+        assert!(parse_pairs(&["team".to_string()]).is_err());
+    }
+}
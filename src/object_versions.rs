@@ -0,0 +1,214 @@
+use aws_sdk_s3::Error;
+use aws_sdk_s3::model::{DeleteMarkerEntry, ObjectVersion};
+use aws_sdk_s3::types::DateTime;
+use fancy_regex::Regex;
+
+use crate::{ClientBucket, OutputPrinter};
+
+/// A version of an object or a delete marker, as listed by `ListObjectVersions`.
+#[derive(Debug, Clone)]
+pub struct VersionRecord {
+    pub key: String,
+    pub version_id: String,
+    pub is_latest: bool,
+    pub is_delete_marker: bool,
+    pub size: i64,
+    pub last_modified: Option<DateTime>,
+}
+
+impl VersionRecord {
+    pub fn from_version(version: &ObjectVersion) -> VersionRecord {
+        VersionRecord {
+            key: version.key().unwrap_or_default().to_string(),
+            version_id: version.version_id().unwrap_or_default().to_string(),
+            is_latest: version.is_latest(),
+            is_delete_marker: false,
+            size: version.size(),
+            last_modified: version.last_modified().cloned(),
+        }
+    }
+
+    pub fn from_delete_marker(marker: &DeleteMarkerEntry) -> VersionRecord {
+        VersionRecord {
+            key: marker.key().unwrap_or_default().to_string(),
+            version_id: marker.version_id().unwrap_or_default().to_string(),
+            is_latest: marker.is_latest(),
+            is_delete_marker: true,
+            size: 0,
+            last_modified: marker.last_modified().cloned(),
+        }
+    }
+
+    /// A record without a size, last modified at `last_modified_secs`. Used by the tests of the
+    /// version operations, which live in the binary and cannot see test-only code of this library.
+    pub fn new(key: &str, version_id: &str, is_latest: bool, is_delete_marker: bool, last_modified_secs: i64) -> VersionRecord {
+        VersionRecord {
+            key: key.to_string(),
+            version_id: version_id.to_string(),
+            is_latest,
+            is_delete_marker,
+            size: 0,
+            last_modified: Some(DateTime::from_secs(last_modified_secs)),
+        }
+    }
+
+    pub fn last_modified_secs(&self) -> i64 {
+        self.last_modified.as_ref().map(|d| d.secs()).unwrap_or_default()
+    }
+}
+
+/// Pages through `list_object_versions` with the key and version id markers, handing out the
+/// versions and delete markers of each page together in the order of S3, by key and newest first.
+/// The common prefixes matching the list regex are collected until they are taken.
+pub struct VersionPages<'a> {
+    client_bucket: &'a ClientBucket,
+    prefix: Option<String>,
+    delimiter: Option<String>,
+    re: Option<Regex>,
+    common_prefixes: Vec<String>,
+    key_marker: Option<String>,
+    version_id_marker: Option<String>,
+    done: bool,
+    pages: u32,
+    versions_scanned: u64,
+}
+
+impl<'a> VersionPages<'a> {
+    /// Versions below `--prefix` with a key matching `--list-regex-pattern`.
+    /// Fails if the pattern is not a valid regex.
+    pub fn new(client_bucket: &'a ClientBucket) -> Result<VersionPages<'a>, String> {
+        let args = &client_bucket.args;
+        let re = match &args.list_regex_pattern {
+            Some(re) => Some(Regex::new(re).map_err(|e| format!("Invalid regex {}: {}", re, e))?),
+            None => None
+        };
+        Ok(VersionPages {
+            prefix: args.prefix.clone(),
+            delimiter: args.delimiter.clone(),
+            re,
+            ..VersionPages::all(client_bucket)
+        })
+    }
+
+    /// Every version in the bucket, regardless of the listing options.
+    pub fn all(client_bucket: &'a ClientBucket) -> VersionPages<'a> {
+        VersionPages {
+            client_bucket,
+            prefix: None,
+            delimiter: None,
+            re: None,
+            common_prefixes: Vec::new(),
+            key_marker: None,
+            version_id_marker: None,
+            done: false,
+            pages: 0,
+            versions_scanned: 0,
+        }
+    }
+
+    pub async fn next_page(&mut self) -> Result<Option<Vec<VersionRecord>>, Error> {
+        if self.done {
+            return Ok(None);
+        }
+        let output = self.client_bucket.client.list_object_versions()
+            .bucket(&self.client_bucket.bucket_name)
+            .set_prefix(self.prefix.clone())
+            .set_delimiter(self.delimiter.clone())
+            .set_key_marker(self.key_marker.take())
+            .set_version_id_marker(self.version_id_marker.take())
+            .send()
+            .await?;
+        self.pages += 1;
+        for common_prefix in output.common_prefixes().unwrap_or_default() {
+            let prefix = common_prefix.prefix().unwrap_or_default();
+            if self.matches(prefix) {
+                self.common_prefixes.push(prefix.to_string());
+            }
+        }
+        self.key_marker = output.next_key_marker().map(|m| m.to_string());
+        self.version_id_marker = output.next_version_id_marker().map(|m| m.to_string());
+        self.done = !output.is_truncated() || self.key_marker.is_none();
+
+        let mut records = merge_delete_markers(
+            output.versions().unwrap_or_default().iter().map(VersionRecord::from_version).collect(),
+            output.delete_markers().unwrap_or_default().iter().map(VersionRecord::from_delete_marker).collect());
+        self.versions_scanned += records.len() as u64;
+        records.retain(|record| self.matches(&record.key));
+        Ok(Some(records))
+    }
+
+    /// The common prefixes of the pages handed out so far, which are only returned when listing with a delimiter.
+    pub fn take_common_prefixes(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.common_prefixes)
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        match &self.re {
+            Some(re) => re.is_match(key).unwrap_or(false),
+            None => true
+        }
+    }
+
+    pub fn print_stats(&self, output_printer: &dyn OutputPrinter) {
+        output_printer.info_output(format!("Scanned {} versions in {} pages", self.versions_scanned, self.pages).as_str());
+    }
+}
+
+/// Merges the delete markers of a page into its versions. S3 lists both by key and newest first,
+/// but hands them out separately, so each list keeps its order and only the entries of the same key
+/// are interleaved: the latest one first, then by last modified date, with a version first on a tie.
+fn merge_delete_markers(versions: Vec<VersionRecord>, markers: Vec<VersionRecord>) -> Vec<VersionRecord> {
+    let mut merged = Vec::with_capacity(versions.len() + markers.len());
+    let mut versions = versions.into_iter().peekable();
+    let mut markers = markers.into_iter().peekable();
+    loop {
+        let take_marker = match (versions.peek(), markers.peek()) {
+            (Some(version), Some(marker)) => {
+                if version.key != marker.key {
+                    marker.key < version.key
+                } else if version.is_latest || marker.is_latest {
+                    marker.is_latest
+                } else {
+                    modified(marker) > modified(version)
+                }
+            }
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (None, None) => break
+        };
+        let next = if take_marker { markers.next() } else { versions.next() };
+        merged.extend(next);
+    }
+    merged
+}
+
+fn modified(record: &VersionRecord) -> (i64, u32) {
+    record.last_modified.as_ref().map(|d| (d.secs(), d.subsec_nanos())).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_merge_delete_markers() {
+        let versions = vec![
+            VersionRecord::new("a", "a3", false, false, 30),
+            VersionRecord::new("a", "a2", false, false, 20),
+            VersionRecord::new("a", "a1", false, false, 10),
+            VersionRecord::new("b", "b2", true, false, 50),
+            VersionRecord::new("b", "b1", false, false, 50),
+        ];
+        let markers = vec![
+            VersionRecord::new("a", "am2", true, true, 30),
+            VersionRecord::new("a", "am1", false, true, 15),
+            VersionRecord::new("ab", "abm1", true, true, 5),
+            VersionRecord::new("b", "bm1", false, true, 50),
+        ];
+        let merged: Vec<String> = merge_delete_markers(versions, markers).into_iter()
+            .map(|record| record.version_id)
+            .collect();
+        assert_eq!(merged, vec!["am2", "a3", "a2", "am1", "a1", "abm1", "b2", "b1", "bm1"]);
+    }
+}
@@ -0,0 +1,127 @@
+use fancy_regex::{Captures, Regex};
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Group(String),
+}
+
+/// Target key template like `archive/$1/${name}.json`, referring to the capture groups of the
+/// list regex by number or by name. `$$` stands for a literal `$`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RenameTemplate {
+    segments: Vec<Segment>,
+}
+
+impl RenameTemplate {
+    pub(crate) fn parse(template: &str) -> Result<RenameTemplate, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+                continue;
+            }
+            let group = match chars.peek() {
+                Some('$') => {
+                    chars.next();
+                    literal.push('$');
+                    continue;
+                }
+                Some('{') => {
+                    chars.next();
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("Missing }} in rename template {}", template)),
+                        }
+                    }
+                    name
+                }
+                Some(c) if c.is_ascii_digit() => take_while(&mut chars, |c| c.is_ascii_digit()),
+                Some(c) if c.is_alphabetic() || *c == '_' => take_while(&mut chars, |c| c.is_alphanumeric() || c == '_'),
+                _ => return Err(format!("Use $$ for a literal $ in rename template {}", template)),
+            };
+            if group.is_empty() {
+                return Err(format!("Empty group name in rename template {}", template));
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Group(group));
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(RenameTemplate { segments })
+    }
+
+    /// Fails for groups which do not exist in the regex, rather than silently expanding them to nothing.
+    pub(crate) fn check_groups(&self, re: &Regex) -> Result<(), String> {
+        for segment in &self.segments {
+            if let Segment::Group(group) = segment {
+                let exists = match group.parse::<usize>() {
+                    Ok(index) => index < re.captures_len(),
+                    Err(_) => re.capture_names().any(|name| name == Some(group.as_str())),
+                };
+                if !exists {
+                    return Err(format!("The list regex pattern has no group {}", group));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Groups which did not take part in the match expand to nothing.
+    pub(crate) fn expand(&self, captures: &Captures) -> String {
+        let mut expanded = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => expanded.push_str(literal),
+                Segment::Group(group) => {
+                    let m = match group.parse::<usize>() {
+                        Ok(index) => captures.get(index),
+                        Err(_) => captures.name(group),
+                    };
+                    expanded.push_str(m.map(|m| m.as_str()).unwrap_or_default());
+                }
+            }
+        }
+        expanded
+    }
+}
+
+fn take_while<I: Iterator<Item=char>>(chars: &mut std::iter::Peekable<I>, accept: fn(char) -> bool) -> String {
+    let mut taken = String::new();
+    while let Some(c) = chars.next_if(|c| accept(*c)) {
+        taken.push(c);
+    }
+    taken
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_expand_template() {
+        let re = Regex::new(r"^exports/(\d{4})/(?P<name>.+)\.csv$").unwrap();
+        let template = RenameTemplate::parse("archive/$1/${name}.json").unwrap();
+        assert!(template.check_groups(&re).is_ok());
+        let captures = re.captures("exports/2022/report.csv").unwrap().unwrap();
+        assert_eq!(template.expand(&captures), "archive/2022/report.json");
+    }
+
+    #[test]
+    fn test_invalid_template() {
+        let re = Regex::new(r"^(.+)\.csv$").unwrap();
+        assert!(RenameTemplate::parse("archive/$2").unwrap().check_groups(&re).is_err());
+        assert!(RenameTemplate::parse("archive/${1").is_err());
+        assert!(RenameTemplate::parse("price$").is_err());
+        assert_eq!(RenameTemplate::parse("a$$b").unwrap(), RenameTemplate { segments: vec![Segment::Literal("a$b".to_string())] });
+    }
+}
@@ -13,7 +13,7 @@ impl ResultSorter {
         self.results.push(obj);
     }
 
-    pub(crate) fn get_sorted(&mut self) -> Vec<Object> {
+    pub(crate) fn get_sorted(mut self) -> Vec<Object> {
         let sorter = match self.asc {
             1 => |a: &Object, b: &Object|
                     a.last_modified().unwrap().secs().cmp(&b.last_modified().unwrap().secs()),
@@ -22,7 +22,6 @@ impl ResultSorter {
             _ => |_: &Object, _: &Object| Ordering::Equal
         };
         self.results.sort_by(sorter);
-        let values: Vec<Object> = self.results.clone();
-        values
+        self.results
     }
 }
@@ -0,0 +1,233 @@
+extern crate glob;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aws_sdk_s3::model::Object;
+use fancy_regex::Regex;
+
+use aws_client::{ClientBucket, OutputPrinter};
+use aws_client::cli::SyncDirection;
+use aws_client::file_delete::delete_objects_in_batches;
+use aws_client::worker_pool::WorkerPool;
+
+use crate::file_download::{download_object_to, file_matches_etag, key_to_path};
+use crate::list_objects::{extract_list_regex_pattern, find_regex, ObjectPages};
+use crate::transfer_policy::{is_transfer_state, local_stats, FileStats};
+use crate::upload_files::upload_file;
+
+use self::glob::{glob, Pattern};
+
+/// Keeps the local folder and the keys below `--prefix` in step. Only files which are missing
+/// or differ on the destination side are transferred, and with `--delete` files which only
+/// exist on the destination side are removed.
+pub(crate) async fn sync_operation(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let args = &client_bucket.args;
+    let local_folder = Path::new(args.local_folder.as_ref().ok_or("Please specify the local folder")?);
+    let direction = args.sync_direction.ok_or("Please specify the sync direction")?;
+    if direction == SyncDirection::Upload && !local_folder.is_dir() {
+        return Err(format!("Cannot find folder {:?}", local_folder));
+    }
+    let key_prefix = folder_prefix(args.prefix.as_deref().unwrap_or_default());
+    let re = extract_list_regex_pattern(&client_bucket);
+    let local_files = list_local_files(local_folder, key_prefix.as_str(), &re)?;
+    let remote_objects = list_remote_objects(client_bucket, key_prefix.as_str(), output_printer).await?;
+    match direction {
+        SyncDirection::Upload => {
+            sync_upload(client_bucket, key_prefix.as_str(), local_files, remote_objects, output_printer).await;
+        }
+        SyncDirection::Download => {
+            sync_download(client_bucket, local_folder, local_files, remote_objects, output_printer).await;
+        }
+    }
+    Ok(())
+}
+
+async fn sync_upload(client_bucket: &ClientBucket,
+                     key_prefix: &str,
+                     local_files: BTreeMap<String, PathBuf>,
+                     mut remote_objects: BTreeMap<String, Object>,
+                     output_printer: &dyn OutputPrinter) {
+    let mut uploads: Vec<(String, String)> = Vec::new();
+    let mut up_to_date = 0;
+    for (relative_key, path) in &local_files {
+        let key = format!("{}{}", key_prefix, relative_key);
+        let obj = remote_objects.remove(relative_key);
+        match out_of_date(client_bucket, path, obj.as_ref(), true).await {
+            Ok(true) => uploads.push((path.to_string_lossy().to_string(), key)),
+            Ok(false) => {
+                output_printer.ok_output(format!("Up to date {}", key).as_str());
+                up_to_date += 1;
+            }
+            Err(e) => output_printer.err_output(e.as_str()),
+        }
+    }
+    let extras: Vec<Object> = remote_objects.into_values().collect();
+    print_summary(uploads.len(), up_to_date, extras.len(), client_bucket, output_printer);
+
+    let mut worker_pool = WorkerPool::new(client_bucket.args.concurrency);
+    for (file_str, key) in &uploads {
+        worker_pool.submit(upload_file(client_bucket, file_str, key, output_printer)).await;
+    }
+    while worker_pool.next().await.is_some() {}
+
+    if client_bucket.args.delete {
+        delete_objects_in_batches(client_bucket, extras, output_printer).await;
+    }
+}
+
+async fn sync_download(client_bucket: &ClientBucket,
+                       local_folder: &Path,
+                       mut local_files: BTreeMap<String, PathBuf>,
+                       remote_objects: BTreeMap<String, Object>,
+                       output_printer: &dyn OutputPrinter) {
+    let mut downloads: Vec<(Object, PathBuf)> = Vec::new();
+    let mut up_to_date = 0;
+    for (relative_key, obj) in remote_objects {
+        local_files.remove(&relative_key);
+        let key = obj.key().unwrap_or_default();
+        let path = match key_to_path(local_folder, relative_key.as_str(), false, None) {
+            Some(path) => path,
+            None => {
+                output_printer.err_output(format!("Cannot map {} to a local path", key).as_str());
+                continue;
+            }
+        };
+        match out_of_date(client_bucket, &path, Some(&obj), false).await {
+            Ok(true) => downloads.push((obj, path)),
+            Ok(false) => {
+                output_printer.ok_output(format!("Up to date {:?}", path).as_str());
+                up_to_date += 1;
+            }
+            Err(e) => output_printer.err_output(e.as_str()),
+        }
+    }
+    print_summary(downloads.len(), up_to_date, local_files.len(), client_bucket, output_printer);
+
+    let mut worker_pool = WorkerPool::new(client_bucket.args.concurrency);
+    for (obj, path) in &downloads {
+        worker_pool.submit(download_object_to(client_bucket, obj, None, path, output_printer)).await;
+    }
+    while worker_pool.next().await.is_some() {}
+
+    if client_bucket.args.delete {
+        for path in local_files.values() {
+            match fs::remove_file(path) {
+                Ok(()) => output_printer.ok_output(format!("Deleted successfully {:?}", path).as_str()),
+                Err(e) => output_printer.err_output(format!("Delete failed {:?}: {}", path, e).as_str()),
+            }
+        }
+    }
+}
+
+/// Files of different sizes always differ. Files of the same size are compared by their MD5 with
+/// `--checksum`, if the ETag of the object is its MD5, and otherwise by their modification time,
+/// the destination being out of date when the source is newer.
+async fn out_of_date(client_bucket: &ClientBucket, path: &Path, obj: Option<&Object>, upload: bool) -> Result<bool, String> {
+    let (obj, local) = match (obj, local_stats(path)) {
+        (Some(obj), Some(local)) => (obj, local),
+        _ => return Ok(true),
+    };
+    let remote = FileStats::from_object(obj);
+    if local.size != remote.size {
+        return Ok(true);
+    }
+    if client_bucket.args.checksum {
+        let key = obj.key().unwrap_or_default();
+        if let Some(matches) = file_matches_etag(client_bucket, path, key, obj.e_tag().unwrap_or_default()).await? {
+            return Ok(!matches);
+        }
+    }
+    let (source, destination) = if upload { (local, remote) } else { (remote, local) };
+    Ok(source.modified > destination.modified)
+}
+
+fn print_summary(transfers: usize, up_to_date: usize, extras: usize, client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) {
+    let extras_action = if client_bucket.args.delete { "to delete" } else { "only on the destination" };
+    output_printer.info_output(format!("Sync: {} to transfer, {} up to date, {} {}",
+                                       transfers, up_to_date, extras, extras_action).as_str());
+}
+
+/// Files below the local folder by their key relative to the folder, filtered by the list regex
+/// applied to the full key they have on S3.
+fn list_local_files(local_folder: &Path, key_prefix: &str, re: &Regex) -> Result<BTreeMap<String, PathBuf>, String> {
+    let mut local_files = BTreeMap::new();
+    let folder_str = local_folder.to_str().ok_or_else(|| format!("Invalid folder name {:?}", local_folder))?;
+    let pattern = format!("{}/**/*", Pattern::escape(folder_str));
+    for entry in glob(pattern.as_str()).map_err(|e| format!("Cannot list {}: {}", folder_str, e))? {
+        let path = match entry {
+            Ok(path) => path,
+            Err(_) => continue
+        };
+        if !path.is_file() || is_transfer_state(&path) {
+            continue;
+        }
+        if let Some(relative_key) = relative_key(local_folder, &path) {
+            if find_regex(format!("{}{}", key_prefix, relative_key).as_str(), re) > -1 {
+                local_files.insert(relative_key, path);
+            }
+        }
+    }
+    Ok(local_files)
+}
+
+/// Objects below the key prefix by their key relative to the prefix. Folder placeholders are left out.
+async fn list_remote_objects(client_bucket: &ClientBucket,
+                             key_prefix: &str,
+                             output_printer: &dyn OutputPrinter) -> Result<BTreeMap<String, Object>, String> {
+    let mut remote_objects = BTreeMap::new();
+    let mut object_pages = ObjectPages::new(client_bucket);
+    while let Some(page) = object_pages.next_page().await
+        .map_err(|e| format!("Could not list bucket: {:?}", e))? {
+        for obj in page {
+            let relative_key = obj.key()
+                .and_then(|key| key.strip_prefix(key_prefix))
+                .filter(|key| !key.is_empty() && !key.ends_with('/'))
+                .map(|key| key.to_string());
+            if let Some(relative_key) = relative_key {
+                remote_objects.insert(relative_key, obj);
+            }
+        }
+    }
+    object_pages.print_stats(output_printer);
+    Ok(remote_objects)
+}
+
+/// A prefix like 'backup' is treated as the folder 'backup/'.
+fn folder_prefix(prefix: &str) -> String {
+    if prefix.is_empty() || prefix.ends_with('/') {
+        prefix.to_string()
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+fn relative_key(local_folder: &Path, path: &Path) -> Option<String> {
+    let segments: Option<Vec<&str>> = path.strip_prefix(local_folder).ok()?
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect();
+    Some(segments?.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_relative_key() {
+        let folder = Path::new("data");
+        let path = folder.join("2022").join("report.csv");
+        assert_eq!(relative_key(folder, &path), Some("2022/report.csv".to_string()));
+        assert_eq!(relative_key(folder, Path::new("other/report.csv")), None);
+    }
+
+    #[test]
+    fn test_folder_prefix() {
+        assert_eq!(folder_prefix(""), "");
+        assert_eq!(folder_prefix("backup"), "backup/");
+        assert_eq!(folder_prefix("backup/"), "backup/");
+    }
+}
@@ -0,0 +1,105 @@
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use aws_sdk_s3::model::Object;
+use aws_smithy_http::result::SdkError;
+
+use aws_client::ClientBucket;
+use aws_client::cli::ExistsPolicy;
+
+/// Size and last modification time in seconds since the epoch of a file or an object.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct FileStats {
+    pub(crate) size: u64,
+    pub(crate) modified: i64,
+}
+
+impl FileStats {
+    pub(crate) fn from_object(obj: &Object) -> FileStats {
+        FileStats {
+            size: obj.size() as u64,
+            modified: obj.last_modified().map(|d| d.secs()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Decides whether a file should be transferred to a destination which may already exist.
+pub(crate) fn should_transfer(policy: ExistsPolicy, source: &FileStats, destination: Option<&FileStats>) -> bool {
+    match destination {
+        None => true,
+        Some(destination) => match policy {
+            ExistsPolicy::Overwrite => true,
+            ExistsPolicy::Skip => false,
+            ExistsPolicy::NewerOnly => source.modified > destination.modified,
+            ExistsPolicy::SizeDiffers => source.size != destination.size,
+        }
+    }
+}
+
+pub(crate) fn local_stats(path: &Path) -> Option<FileStats> {
+    let metadata = path.metadata().ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(FileStats { size: metadata.len(), modified: modified as i64 })
+}
+
+/// Stats of the object with the given key, `None` if there is no such object.
+pub(crate) async fn remote_stats(client_bucket: &ClientBucket, key: &str) -> Result<Option<FileStats>, String> {
+    let res = client_bucket.client
+        .head_object()
+        .bucket(&client_bucket.bucket_name)
+        .key(key)
+        .send()
+        .await;
+    match res {
+        Ok(head) => Ok(Some(FileStats {
+            size: head.content_length() as u64,
+            modified: head.last_modified().map(|d| d.secs()).unwrap_or_default(),
+        })),
+        Err(SdkError::ServiceError { err, .. }) if err.is_not_found() => Ok(None),
+        Err(e) => Err(format!("Cannot read {} due to {:?}", key, e))
+    }
+}
+
+/// Partial downloads and the state of interrupted transfers are not content to be uploaded or synced.
+pub(crate) fn is_transfer_state(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    if name.ends_with(".upload-state") || name.ends_with(".part.state") {
+        return true;
+    }
+    name.ends_with(".part") && Path::new(format!("{}.state", name).as_str()).exists()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const OLD_SMALL: FileStats = FileStats { size: 10, modified: 1000 };
+    const NEW_LARGE: FileStats = FileStats { size: 20, modified: 2000 };
+
+    #[test]
+    fn test_missing_destination_is_always_transferred() {
+        assert!(should_transfer(ExistsPolicy::Skip, &OLD_SMALL, None));
+    }
+
+    #[test]
+    fn test_existing_destination() {
+        assert!(should_transfer(ExistsPolicy::Overwrite, &OLD_SMALL, Some(&NEW_LARGE)));
+        assert!(!should_transfer(ExistsPolicy::Skip, &NEW_LARGE, Some(&OLD_SMALL)));
+        assert!(should_transfer(ExistsPolicy::NewerOnly, &NEW_LARGE, Some(&OLD_SMALL)));
+        assert!(!should_transfer(ExistsPolicy::NewerOnly, &OLD_SMALL, Some(&NEW_LARGE)));
+        assert!(should_transfer(ExistsPolicy::SizeDiffers, &OLD_SMALL, Some(&NEW_LARGE)));
+        assert!(!should_transfer(ExistsPolicy::SizeDiffers, &OLD_SMALL, Some(&OLD_SMALL)));
+    }
+
+    #[test]
+    fn test_is_transfer_state() {
+        assert!(is_transfer_state(Path::new("data").join("backup.zip.upload-state").as_path()));
+        assert!(is_transfer_state(Path::new("data").join("report.csv.part.state").as_path()));
+        assert!(!is_transfer_state(Path::new("data").join("report.csv").as_path()));
+        assert!(!is_transfer_state(Path::new("data").join("missing-state.part").as_path()));
+    }
+}
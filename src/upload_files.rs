@@ -1,18 +1,33 @@
 extern crate glob;
 
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
 
+use aws_sdk_s3::Error;
 use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
 use aws_smithy_http::byte_stream::{ByteStream, Length};
+use aws_smithy_http::result::SdkError;
 use simple_error::{SimpleError};
 
 use aws_client::{ClientBucket, OutputPrinter};
+use aws_client::cli::{ExistsPolicy, Operation};
+use aws_client::worker_pool::WorkerPool;
 
+use crate::checksum::{etag_is_md5, file_md5, md5_base64, md5_hex, multipart_etag, trim_etag};
+use crate::transfer_policy::{is_transfer_state, local_stats, remote_stats, should_transfer};
 use crate::upload_object;
+use crate::upload_state::UploadState;
 
 use self::glob::glob;
 
 const MAX_CHUNKS: u64 = 10000;
+pub(crate) const MIB: u64 = 1024 * 1024;
+const MIN_PART_SIZE: u64 = 5 * MIB;
+const MAX_PART_SIZE: u64 = 5 * 1024 * MIB;
+const MAX_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * MIB;
+pub(crate) const DEFAULT_MULTIPART_THRESHOLD: u64 = 100;
 
 
 pub(crate) async fn upload_files_operation(glob_pattern: &String,
@@ -21,29 +36,32 @@ pub(crate) async fn upload_files_operation(glob_pattern: &String,
     let expected = format!("Failed to read glob pattern {}", glob_pattern);
     let target_folder = &client_bucket.args.target_folder;
     let flatten = &client_bucket.args.flatten;
-    let bucket_name = &client_bucket.bucket_name;
+    let if_exists = client_bucket.args.if_exists.unwrap_or(ExistsPolicy::Overwrite);
     match target_folder {
         Some(tf) => {
             for entry in glob(glob_pattern).expect(&expected) {
                 match entry {
+                    Ok(path) if is_transfer_state(&path) => {}
                     Ok(path) => {
                         let path_string = path.clone().into_os_string();
                         let file_name = if *flatten { path.file_name().unwrap() } else { path_string.as_os_str() };
                         let file_str = file_name.to_str().unwrap().replace("\\", "/");
                         let key = format!("{}/{}", tf, file_str);
                         let file_str = path.to_str().unwrap();
-                        output_printer.ok_output(format!("Uploading {} to {}", file_str, key).as_str());
-                        let res = upload_object(&client_bucket.client,
-                                                bucket_name.as_str(),
-                                                file_str, key.as_str()).await;
-                        match res {
-                            Ok(_) => {
-                                output_printer.ok_output(format!("Upload successful: {}", key).as_str());
-                            }
-                            Err(e) => {
-                                output_printer.err_output(format!("Could not upload: {}", e).as_str());
+                        if if_exists != ExistsPolicy::Overwrite {
+                            match skip_existing(client_bucket, &path, key.as_str(), if_exists).await {
+                                Ok(true) => {
+                                    output_printer.ok_output(format!("Skipped {} as {} already exists", file_str, key).as_str());
+                                    continue;
+                                }
+                                Ok(false) => {}
+                                Err(e) => {
+                                    output_printer.err_output(e.as_str());
+                                    continue;
+                                }
                             }
                         }
+                        upload_file(client_bucket, file_str, key.as_str(), output_printer).await;
                     }
                     Err(_) => {}
                 }
@@ -55,93 +73,464 @@ pub(crate) async fn upload_files_operation(glob_pattern: &String,
     }
 }
 
+/// Uploads a single file, in parts if it is larger than `--multipart-threshold`.
+pub(crate) async fn upload_file(client_bucket: &ClientBucket,
+                                file_str: &str,
+                                key: &str,
+                                output_printer: &dyn OutputPrinter) {
+    let multipart_threshold = client_bucket.args.multipart_threshold
+        .unwrap_or(DEFAULT_MULTIPART_THRESHOLD) * MIB;
+    let file_size = Path::new(file_str).metadata().map(|m| m.len()).unwrap_or_default();
+    if file_size > multipart_threshold {
+        output_printer.info_output(format!("Uploading {} to {} in parts", file_str, key).as_str());
+        let res = upload_file_multipart(client_bucket, file_str, key, output_printer).await;
+        if let Err(e) = res {
+            output_printer.err_output(format!("Could not upload: {}", e).as_str());
+        }
+        return;
+    }
+    output_printer.info_output(format!("Uploading {} to {}", file_str, key).as_str());
+    let res = upload_object(&client_bucket.client,
+                            client_bucket.bucket_name.as_str(),
+                            file_str, key).await;
+    match res {
+        Ok(_) => {
+            output_printer.ok_output(format!("Upload successful: {}", key).as_str());
+        }
+        Err(e) => {
+            output_printer.err_output(format!("Could not upload: {}", e).as_str());
+        }
+    }
+}
+
+async fn skip_existing(client_bucket: &ClientBucket, path: &Path, key: &str, if_exists: ExistsPolicy) -> Result<bool, String> {
+    let local = local_stats(path).ok_or_else(|| format!("Cannot read {:?}", path))?;
+    let remote = remote_stats(client_bucket, key).await?;
+    Ok(!should_transfer(if_exists, &local, remote.as_ref()))
+}
+
+pub(crate) const DEFAULT_CHUNK_SIZE: u64 = 5;
+const MAX_PART_ATTEMPTS: u32 = 3;
+/// Error codes of S3 for requests which may succeed when they are sent again.
+const TRANSIENT_ERROR_CODES: [&str; 4] = ["InternalError", "ServiceUnavailable", "SlowDown", "RequestTimeout"];
+
+/// Why uploading a file in parts failed. Only a transient failure, like a lost connection,
+/// keeps the multipart upload so that it can be resumed.
+struct UploadFailure {
+    error: SimpleError,
+    transient: bool,
+}
 
-const DEFAULT_CHUNK_SIZE: u64 = 5;
+impl UploadFailure {
+    fn fatal(msg: String) -> UploadFailure {
+        UploadFailure { error: SimpleError::new(msg), transient: false }
+    }
+}
+
+/// True for failed requests which may succeed later: connection problems, timeouts and the
+/// error codes of `TRANSIENT_ERROR_CODES`.
+fn is_transient<E>(e: &SdkError<E>, code: Option<&str>) -> bool {
+    match e {
+        SdkError::ServiceError { .. } => matches!(code, Some(code) if TRANSIENT_ERROR_CODES.contains(&code)),
+        SdkError::ConstructionFailure(_) => false,
+        _ => true
+    }
+}
 
 pub(crate) async fn upload_file_in_chunks(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), SimpleError> {
-    let bucket_name = &client_bucket.bucket_name;
-    let chunk_size: u64 = client_bucket.args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
     let file_name: &str = client_bucket.args.upload_file.as_ref().expect("Please specify the file name").as_str();
     let file_path = Path::new(file_name);
-    let chunk_size_bytes = chunk_size * 1024 * 1024;
     if !file_path.exists() {
         return Err(SimpleError::new(format!("Cannot find file {}.", file_name.to_string()).as_str()));
     }
     let key = file_path.file_name().unwrap().to_str().unwrap();
-    output_printer.ok_output(format!("Uploading to {}", bucket_name).as_str());
+    upload_file_multipart(client_bucket, file_name, key, output_printer).await
+}
 
-    let result = client_bucket.client
-        .create_multipart_upload()
-        .bucket(bucket_name)
-        .key(key)
-        .send()
-        .await;
-    if result.is_err() {
-        return Err(SimpleError::new(format!("Cannot start multi part upload {:?}.", result.err().unwrap()).as_str()));
-    }
-    let result_output = result.unwrap();
-    let upload_id = result_output.upload_id().expect("Missing upload id");
-    let file_size = tokio::fs::metadata(file_path).await
-        .expect(format!("Cannot access file: {}", file_path.to_str().unwrap()).as_str()).len();
+/// Uploads a file in parts. With upload-large the progress is kept in a state file, so that
+/// an upload interrupted by a transient failure is resumed by running the same command again.
+/// Other failures abort the upload, so that its parts are not kept and billed.
+pub(crate) async fn upload_file_multipart(client_bucket: &ClientBucket,
+                                          file_name: &str,
+                                          key: &str,
+                                          output_printer: &dyn OutputPrinter) -> Result<(), SimpleError> {
+    let bucket_name = &client_bucket.bucket_name;
+    let chunk_size: u64 = client_bucket.args.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let file_path = Path::new(file_name);
+    let chunk_size_bytes = chunk_size.saturating_mul(MIB);
+    let (file_size, modified) = size_and_modified(file_path)
+        .expect(format!("Cannot access file: {}", file_path.to_str().unwrap()).as_str());
 
     if file_size == 0 {
         return Err(SimpleError::new(format!("File is empty {}.", file_name)));
     }
 
-    let (chunk_count, size_of_last_chunk) = calculate_chunks(file_name, file_size,
-                                                             chunk_size_bytes.clone());
+    let mut state_path = Some(UploadState::state_path(file_name))
+        .filter(|_| matches!(client_bucket.args.mode, Operation::UploadLarge));
+    let resumed = match &state_path {
+        Some(state_path) => resume_upload(client_bucket, file_path, key, file_size, modified, state_path, output_printer).await,
+        None => None
+    };
+    let (state, completed_parts) = match resumed {
+        Some(resumed) => resumed,
+        None => {
+            let part_size = calculate_part_size(file_name, file_size, chunk_size_bytes)?;
+            output_printer.info_output(format!("Uploading to {}", bucket_name).as_str());
+            let result = client_bucket.client
+                .create_multipart_upload()
+                .bucket(bucket_name)
+                .key(key)
+                .send()
+                .await;
+            if result.is_err() {
+                return Err(SimpleError::new(format!("Cannot start multi part upload {:?}.", result.err().unwrap()).as_str()));
+            }
+            let result_output = result.unwrap();
+            let state = UploadState {
+                bucket: bucket_name.to_string(),
+                key: key.to_string(),
+                upload_id: result_output.upload_id().expect("Missing upload id").to_string(),
+                part_size,
+                file_size,
+                modified,
+                parts: Vec::new(),
+            };
+            if let Some(path) = &state_path {
+                if let Err(e) = state.save(path) {
+                    output_printer.err_output(format!("Cannot write upload state {:?}, the upload cannot be resumed: {}", path, e).as_str());
+                    state_path = None;
+                }
+            }
+            (state, Vec::new())
+        }
+    };
 
-    let mut upload_parts: Vec<CompletedPart> = Vec::new();
-    for chunk_index in 0..chunk_count {
-        let this_chunk = if chunk_count - 1 == chunk_index {
-            size_of_last_chunk
-        } else {
-            chunk_size_bytes
-        };
-        let stream = ByteStream::read_from()
-            .path(file_path)
-            .offset(chunk_index * chunk_size_bytes)
-            .length(Length::Exact(this_chunk))
-            .build()
-            .await
-            .unwrap();
-        let part_number = (chunk_index as i32) + 1;
-        let upload_part_res = client_bucket.client
-            .upload_part()
-            .key(key)
-            .bucket(bucket_name)
-            .upload_id(upload_id)
-            .body(stream)
-            .part_number(part_number)
-            .send()
-            .await;
-        upload_parts.push(CompletedPart::builder()
-                              .e_tag(upload_part_res.unwrap().e_tag.unwrap_or_default())
-                              .part_number(part_number)
-                              .build(),
-        )
-    }
+    let part_uploader = PartUploader {
+        client_bucket,
+        file_path,
+        key,
+        upload_id: state.upload_id.as_str(),
+        part_size: state.part_size,
+        file_size,
+    };
+    let res = part_uploader.upload_parts(completed_parts, state_path.as_deref(), output_printer).await;
+    let upload_parts = match res {
+        Ok(upload_parts) => upload_parts,
+        Err(failure) => {
+            part_uploader.keep_for_resume(failure.transient, &state, state_path.as_deref(), output_printer).await;
+            return Err(failure.error);
+        }
+    };
 
+    let part_e_tags: Vec<&str> = upload_parts.iter().map(|part| part.e_tag().unwrap_or_default()).collect();
+    let expected_e_tag = multipart_etag(&part_e_tags);
     let completed_multipart_upload: CompletedMultipartUpload = CompletedMultipartUpload::builder()
         .set_parts(Some(upload_parts))
         .build();
 
-    let _complete_multipart_upload_res = client_bucket.client
+    let complete_multipart_upload_res = client_bucket.client
         .complete_multipart_upload()
         .bucket(bucket_name)
         .key(key)
         .multipart_upload(completed_multipart_upload)
-        .upload_id(upload_id)
+        .upload_id(state.upload_id.as_str())
         .send()
-        .await
-        .unwrap();
+        .await;
+    let complete_output = match complete_multipart_upload_res {
+        Ok(output) => output,
+        Err(e) => {
+            let code = match &e {
+                SdkError::ServiceError { err, .. } => err.code(),
+                _ => None
+            };
+            part_uploader.keep_for_resume(is_transient(&e, code), &state, state_path.as_deref(), output_printer).await;
+            return Err(SimpleError::new(format!("Cannot complete multi part upload {:?}.", e)));
+        }
+    };
+    if let Some(state_path) = &state_path {
+        let _ = fs::remove_file(state_path);
+    }
+    let e_tag = trim_etag(complete_output.e_tag().unwrap_or_default());
+    // The parts are uploaded without SSE-C, which is not reported when completing the upload
+    if etag_is_md5(complete_output.server_side_encryption(), None) && expected_e_tag.as_deref() != Some(e_tag) {
+        return Err(SimpleError::new(format!("ETag {} of {} does not match the expected {:?}.",
+                                            e_tag, key, expected_e_tag)));
+    }
 
     output_printer.ok_output(format!("File {} uploaded successfully", file_name).as_str());
 
     Ok(())
 }
 
-fn calculate_chunks(file_name: &str, file_size: u64, chunk_size_bytes: u64) -> (u64, u64) {
+/// Picks up the upload recorded in the state file and returns the parts S3 already has for it.
+/// Returns `None` when there is nothing to resume and a fresh upload has to be started.
+async fn resume_upload(client_bucket: &ClientBucket,
+                       file_path: &Path,
+                       key: &str,
+                       file_size: u64,
+                       modified: u64,
+                       state_path: &Path,
+                       output_printer: &dyn OutputPrinter) -> Option<(UploadState, Vec<CompletedPart>)> {
+    let state = UploadState::load(state_path)?;
+    let part_uploader = PartUploader {
+        client_bucket,
+        file_path,
+        key,
+        upload_id: state.upload_id.as_str(),
+        part_size: state.part_size,
+        file_size,
+    };
+    if !state.matches(&client_bucket.bucket_name, key, file_size, modified) {
+        output_printer.info_output(format!("{} changed since the last upload attempt, starting a fresh upload", key).as_str());
+        if state.bucket == client_bucket.bucket_name && state.key == key {
+            part_uploader.abort(output_printer).await;
+        }
+        return None;
+    }
+    match part_uploader.list_uploaded_parts().await {
+        Ok(parts) => {
+            let parts = recorded_parts(parts, &state.parts);
+            output_printer.info_output(format!("Resuming upload of {} with {} parts already uploaded",
+                                               key, parts.len()).as_str());
+            Some((state, parts))
+        }
+        Err(e) => {
+            output_printer.info_output(format!("Cannot resume upload of {}, starting a fresh upload: {:?}", key, e).as_str());
+            None
+        }
+    }
+}
+
+fn size_and_modified(file_path: &Path) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(file_path).ok()?;
+    let modified = metadata.modified().ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    Some((metadata.len(), modified))
+}
+
+/// The uploaded parts without those whose ETag differs from the one recorded in the state file,
+/// as these do not hold the content which was uploaded for them. Parts which were uploaded but
+/// not recorded before an interruption are kept, their content was checked with Content-MD5.
+fn recorded_parts(parts: Vec<CompletedPart>, recorded: &[(i32, String)]) -> Vec<CompletedPart> {
+    let recorded: HashMap<i32, &str> = recorded.iter()
+        .map(|(part_number, e_tag)| (*part_number, trim_etag(e_tag)))
+        .collect();
+    parts.into_iter()
+        .filter(|part| match recorded.get(&part.part_number()) {
+            Some(e_tag) => *e_tag == trim_etag(part.e_tag().unwrap_or_default()),
+            None => true
+        })
+        .collect()
+}
+
+/// Uploads the parts of one file into an already created multipart upload.
+struct PartUploader<'a> {
+    client_bucket: &'a ClientBucket,
+    file_path: &'a Path,
+    key: &'a str,
+    upload_id: &'a str,
+    part_size: u64,
+    file_size: u64,
+}
+
+impl<'a> PartUploader<'a> {
+    /// Uploads all parts missing from `completed_parts` with up to `--concurrency` requests in flight
+    /// and returns all parts ordered by part number. Every finished part is recorded in the state file,
+    /// until writing it fails once.
+    async fn upload_parts(&self,
+                          completed_parts: Vec<CompletedPart>,
+                          state_path: Option<&Path>,
+                          output_printer: &dyn OutputPrinter) -> Result<Vec<CompletedPart>, UploadFailure> {
+        let (chunk_count, _) = calculate_chunks(self.key, self.file_size, self.part_size)
+            .map_err(|e| UploadFailure { error: e, transient: false })?;
+        let completed: HashSet<i32> = completed_parts.iter().map(|part| part.part_number()).collect();
+        let mut upload_parts: Vec<CompletedPart> = completed_parts;
+        let mut worker_pool = WorkerPool::new(self.client_bucket.args.concurrency);
+        let mut state_path = state_path;
+        let mut record_part = |part: CompletedPart| {
+            if let Some(path) = state_path {
+                if let Err(e) = UploadState::append_part(path, part.part_number(), part.e_tag().unwrap_or_default()) {
+                    output_printer.err_output(format!("Cannot write upload state {:?}, parts are no longer recorded: {}", path, e).as_str());
+                    state_path = None;
+                }
+            }
+            upload_parts.push(part);
+        };
+        for chunk_index in 0..chunk_count {
+            let part_number = (chunk_index as i32) + 1;
+            if completed.contains(&part_number) {
+                continue;
+            }
+            if let Some(finished) = worker_pool.submit(self.upload_part(part_number, output_printer)).await {
+                record_part(finished?);
+            }
+        }
+        while let Some(finished) = worker_pool.next().await {
+            record_part(finished?);
+        }
+        upload_parts.sort_by_key(|part| part.part_number());
+        Ok(upload_parts)
+    }
+
+    /// Uploads a part, retrying transient failures up to `MAX_PART_ATTEMPTS` times.
+    async fn upload_part(&self, part_number: i32, output_printer: &dyn OutputPrinter) -> Result<CompletedPart, UploadFailure> {
+        let offset = (part_number as u64 - 1) * self.part_size;
+        let length = self.part_size.min(self.file_size - offset);
+        let digest = file_md5(self.file_path, offset, length).await
+            .map_err(|e| UploadFailure::fatal(format!("Cannot read part {} of {:?}: {}", part_number, self.file_path, e)))?;
+        let md5 = md5_hex(&digest);
+        let content_md5 = md5_base64(&digest);
+        let mut attempt = 1;
+        loop {
+            let upload_part_res = self.send_part(part_number, offset, length, content_md5.as_str(), md5.as_str()).await;
+            match upload_part_res {
+                Ok(e_tag) => {
+                    output_printer.info_output(format!("Uploaded part {} of {}", part_number, self.key).as_str());
+                    return Ok(CompletedPart::builder()
+                        .e_tag(e_tag)
+                        .part_number(part_number)
+                        .build());
+                }
+                Err(failure) if failure.transient && attempt < MAX_PART_ATTEMPTS => {
+                    output_printer.info_output(format!("Retrying part {} of {} after attempt {} failed: {}",
+                                                       part_number, self.key, attempt, failure.error).as_str());
+                    tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                    attempt += 1;
+                }
+                Err(failure) => {
+                    return Err(UploadFailure {
+                        error: SimpleError::new(format!("Cannot upload part {} of {} after {} attempts: {}",
+                                                        part_number, self.key, attempt, failure.error)),
+                        transient: failure.transient,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Sends a part straight from the file and returns its ETag, checked against the MD5 of the part.
+    async fn send_part(&self, part_number: i32, offset: u64, length: u64, content_md5: &str, md5: &str) -> Result<String, UploadFailure> {
+        let body = ByteStream::read_from()
+            .path(self.file_path)
+            .offset(offset)
+            .length(Length::Exact(length))
+            .build()
+            .await
+            .map_err(|e| UploadFailure::fatal(format!("{:?}", e)))?;
+        let output = self.client_bucket.client
+            .upload_part()
+            .key(self.key)
+            .bucket(&self.client_bucket.bucket_name)
+            .upload_id(self.upload_id)
+            .content_md5(content_md5)
+            .body(body)
+            .part_number(part_number)
+            .send()
+            .await
+            .map_err(|e| {
+                let code = match &e {
+                    SdkError::ServiceError { err, .. } => err.code(),
+                    _ => None
+                };
+                UploadFailure { error: SimpleError::new(format!("{:?}", e)), transient: is_transient(&e, code) }
+            })?;
+        let e_tag = output.e_tag().unwrap_or_default();
+        if etag_is_md5(output.server_side_encryption(), output.sse_customer_algorithm()) && trim_etag(e_tag) != md5 {
+            // The part was corrupted on the way, sending it again may work
+            return Err(UploadFailure {
+                error: SimpleError::new(format!("ETag {} does not match the MD5 {} of the part", e_tag, md5)),
+                transient: true,
+            });
+        }
+        Ok(e_tag.to_string())
+    }
+
+    /// Lists the parts S3 already received, keeping only those with the size this upload expects.
+    async fn list_uploaded_parts(&self) -> Result<Vec<CompletedPart>, Error> {
+        let mut parts: Vec<CompletedPart> = Vec::new();
+        let mut part_number_marker: Option<String> = None;
+        loop {
+            let output = self.client_bucket.client
+                .list_parts()
+                .bucket(&self.client_bucket.bucket_name)
+                .key(self.key)
+                .upload_id(self.upload_id)
+                .set_part_number_marker(part_number_marker)
+                .send()
+                .await?;
+            for part in output.parts().unwrap_or_default() {
+                let offset = (part.part_number() as u64 - 1) * self.part_size;
+                if offset < self.file_size && part.size() as u64 == self.part_size.min(self.file_size - offset) {
+                    parts.push(CompletedPart::builder()
+                        .set_e_tag(part.e_tag().map(|e| e.to_string()))
+                        .part_number(part.part_number())
+                        .build());
+                }
+            }
+            part_number_marker = output.next_part_number_marker().map(|m| m.to_string());
+            if !output.is_truncated() || part_number_marker.is_none() {
+                break;
+            }
+        }
+        Ok(parts)
+    }
+
+    /// Keeps an upload which failed for a transient reason, together with its state file, so that
+    /// running the upload again resumes it. Otherwise, or without a state file, or when the file was
+    /// changed or removed since the upload started, the upload is aborted.
+    async fn keep_for_resume(&self, transient: bool, state: &UploadState, state_path: Option<&Path>, output_printer: &dyn OutputPrinter) {
+        let unchanged = size_and_modified(self.file_path) == Some((state.file_size, state.modified));
+        match state_path {
+            Some(_) if transient && unchanged => {
+                output_printer.info_output(format!("Run the upload of {} again to resume it", self.key).as_str());
+            }
+            _ => {
+                self.abort(output_printer).await;
+                if let Some(state_path) = state_path {
+                    let _ = fs::remove_file(state_path);
+                }
+            }
+        }
+    }
+
+    async fn abort(&self, output_printer: &dyn OutputPrinter) {
+        let res = self.client_bucket.client
+            .abort_multipart_upload()
+            .bucket(&self.client_bucket.bucket_name)
+            .key(self.key)
+            .upload_id(self.upload_id)
+            .send()
+            .await;
+        match res {
+            Ok(_) => {
+                output_printer.info_output(format!("Aborted multi part upload of {}", self.key).as_str());
+            }
+            Err(e) => {
+                output_printer.err_output(format!("Could not abort multi part upload {} of {}: {:?}",
+                                                  self.upload_id, self.key, e).as_str());
+            }
+        }
+    }
+}
+
+/// Picks the smallest part size of at least `min_part_size` bytes, rounded to whole MiB,
+/// which keeps the file within the S3 limit of `MAX_CHUNKS` parts.
+fn calculate_part_size(file_name: &str, file_size: u64, min_part_size: u64) -> Result<u64, SimpleError> {
+    if min_part_size < MIN_PART_SIZE || min_part_size > MAX_PART_SIZE {
+        return Err(SimpleError::new(format!("Chunk size of {} MiB is outside of the S3 part size limits of 5 MiB to 5 GiB.",
+                                            min_part_size / MIB)));
+    }
+    if file_size > MAX_OBJECT_SIZE {
+        return Err(SimpleError::new(format!("File {} is larger than the S3 object size limit of 5 TiB.", file_name)));
+    }
+    let required_part_size = (file_size + MAX_CHUNKS - 1) / MAX_CHUNKS;
+    let required_part_size = (required_part_size + MIB - 1) / MIB * MIB;
+    Ok(min_part_size.max(required_part_size))
+}
+
+fn calculate_chunks(file_name: &str, file_size: u64, chunk_size_bytes: u64) -> Result<(u64, u64), SimpleError> {
     let mut chunk_count = file_size / chunk_size_bytes + 1;
     let mut size_of_last_chunk = file_size % chunk_size_bytes;
     if size_of_last_chunk == 0 {
@@ -149,9 +538,9 @@ fn calculate_chunks(file_name: &str, file_size: u64, chunk_size_bytes: u64) -> (
         chunk_count -= 1;
     }
     if chunk_count > MAX_CHUNKS {
-        panic!("Too many chunks {}.", file_name);
+        return Err(SimpleError::new(format!("Too many chunks {}.", file_name)));
     }
-    (chunk_count, size_of_last_chunk)
+    Ok((chunk_count, size_of_last_chunk))
 }
 
 #[cfg(test)]
@@ -167,8 +556,39 @@ mod tests {
         let file_size = (file_size_mb * 1024 * 1024) as u64;
         let chunk_size_mb = 2;
         let chunk_size_bytes = (chunk_size_mb * 1024 * 1024) as u64;
-        let (chunk_count, _) = calculate_chunks(file_name, file_size, chunk_size_bytes);
+        let (chunk_count, _) = calculate_chunks(file_name, file_size, chunk_size_bytes).unwrap();
         assert_eq!(chunk_count, file_size_mb / chunk_size_mb);
     }
 
+    #[test]
+    fn test_recorded_parts() {
+        let part = |part_number: i32, e_tag: &str| CompletedPart::builder().part_number(part_number).e_tag(e_tag).build();
+        let recorded = vec![(1, "\"e1\"".to_string()), (2, "\"e2\"".to_string())];
+        let parts = recorded_parts(vec![part(1, "\"e1\""), part(2, "\"other\""), part(3, "\"e3\"")], &recorded);
+        let part_numbers: Vec<i32> = parts.iter().map(|part| part.part_number()).collect();
+        assert_eq!(part_numbers, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_calculate_part_size_keeps_chunk_size() {
+        let part_size = calculate_part_size("text.mp3", 100 * MIB, 8 * MIB).unwrap();
+        assert_eq!(part_size, 8 * MIB);
+    }
+
+    #[test]
+    fn test_calculate_part_size_grows_for_large_files() {
+        let file_size = 100 * 1024 * MIB;
+        let part_size = calculate_part_size("backup.zip", file_size, MIN_PART_SIZE).unwrap();
+        assert_eq!(part_size, 11 * MIB);
+        let (chunk_count, _) = calculate_chunks("backup.zip", file_size, part_size).unwrap();
+        assert!(chunk_count <= MAX_CHUNKS);
+    }
+
+    #[test]
+    fn test_calculate_part_size_rejects_invalid_chunk_size() {
+        assert!(calculate_part_size("text.mp3", 100 * MIB, 2 * MIB).is_err());
+        assert!(calculate_part_size("text.mp3", 100 * MIB, 6 * 1024 * MIB).is_err());
+        assert!(calculate_part_size("text.mp3", MAX_OBJECT_SIZE + 1, MIN_PART_SIZE).is_err());
+    }
+
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Progress of a multipart upload, kept in a file next to the uploaded file so that an
+/// interrupted upload can be resumed by running the same command again.
+#[derive(Debug, PartialEq)]
+pub(crate) struct UploadState {
+    pub(crate) bucket: String,
+    pub(crate) key: String,
+    pub(crate) upload_id: String,
+    pub(crate) part_size: u64,
+    pub(crate) file_size: u64,
+    pub(crate) modified: u64,
+    pub(crate) parts: Vec<(i32, String)>,
+}
+
+impl UploadState {
+    pub(crate) fn state_path(file_name: &str) -> PathBuf {
+        PathBuf::from(format!("{}.upload-state", file_name))
+    }
+
+    pub(crate) fn load(path: &Path) -> Option<UploadState> {
+        let content = fs::read_to_string(path).ok()?;
+        UploadState::parse(&content)
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    /// Appends a completed part, so that the whole file does not have to be rewritten for every part.
+    pub(crate) fn append_part(path: &Path, part_number: i32, e_tag: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        writeln!(file, "part={} {}", part_number, e_tag)
+    }
+
+    /// True if the state belongs to the same target and the source file was not changed since.
+    pub(crate) fn matches(&self, bucket: &str, key: &str, file_size: u64, modified: u64) -> bool {
+        self.bucket == bucket && self.key == key && self.file_size == file_size && self.modified == modified
+    }
+
+    fn parse(content: &str) -> Option<UploadState> {
+        let mut state = UploadState {
+            bucket: String::new(),
+            key: String::new(),
+            upload_id: String::new(),
+            part_size: 0,
+            file_size: 0,
+            modified: 0,
+            parts: Vec::new(),
+        };
+        for line in content.lines() {
+            let (name, value) = line.split_once('=')?;
+            match name {
+                "bucket" => state.bucket = value.to_string(),
+                "key" => state.key = value.to_string(),
+                "upload_id" => state.upload_id = value.to_string(),
+                "part_size" => state.part_size = value.parse().ok()?,
+                "file_size" => state.file_size = value.parse().ok()?,
+                "modified" => state.modified = value.parse().ok()?,
+                "part" => {
+                    let (part_number, e_tag) = value.split_once(' ')?;
+                    state.parts.push((part_number.parse().ok()?, e_tag.to_string()));
+                }
+                _ => {}
+            }
+        }
+        if state.upload_id.is_empty() || state.part_size == 0 {
+            return None;
+        }
+        Some(state)
+    }
+}
+
+impl fmt::Display for UploadState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bucket={}\nkey={}\nupload_id={}\npart_size={}\nfile_size={}\nmodified={}\n",
+               self.bucket, self.key, self.upload_id, self.part_size, self.file_size, self.modified)?;
+        for (part_number, e_tag) in &self.parts {
+            writeln!(f, "part={} {}", part_number, e_tag)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_written_state() {
+        let state = UploadState {
+            bucket: "mdm-eu-prod-republish".to_string(),
+            key: "backup.zip".to_string(),
+            upload_id: "abc.def-1".to_string(),
+            part_size: 5 * 1024 * 1024,
+            file_size: 12 * 1024 * 1024,
+            modified: 1665000000,
+            parts: vec![(1, "\"e1\"".to_string()), (3, "\"e3\"".to_string())],
+        };
+        assert_eq!(UploadState::parse(state.to_string().as_str()), Some(state));
+    }
+
+    #[test]
+    fn test_parse_incomplete_state() {
+        assert_eq!(UploadState::parse("bucket=b\nkey=k\n"), None);
+    }
+}
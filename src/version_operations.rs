@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use aws_sdk_s3::model::Object;
+
+use aws_client::{ClientBucket, OutputPrinter};
+use aws_client::cli::{Cli, ExistsPolicy};
+use aws_client::file_delete::delete_versions_in_batches;
+use aws_client::object_versions::VersionPages;
+use aws_client::worker_pool::WorkerPool;
+
+use crate::copy_operations::copy_from_key_to_target;
+use crate::file_download::{download_object_to, key_to_path};
+use crate::transfer_policy::{local_stats, should_transfer, FileStats};
+
+/// The versions given by `--versions-file` or else by `--source-key` and `--version-id`.
+pub(crate) fn selected_versions(args: &Cli) -> Result<Vec<(String, String)>, String> {
+    if let Some(versions_file) = &args.versions_file {
+        let content = fs::read_to_string(versions_file)
+            .map_err(|e| format!("Cannot read {}: {}", versions_file, e))?;
+        let sep = args.sep.as_deref().unwrap_or(",");
+        return Ok(parse_versions(content.as_str(), sep));
+    }
+    let key = args.source_key.as_ref().ok_or("Source key is missing. Please specify the source key")?;
+    let version_id = args.version_id.as_ref().ok_or("Please specify the version id")?;
+    Ok(vec![(key.clone(), version_id.clone())])
+}
+
+/// Downloads the selected versions below `--target-folder`. When a key is selected with several
+/// versions, the version id is appended to the file name of each of them.
+pub(crate) async fn download_versions(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let versions = selected_versions(&client_bucket.args)?;
+    let versions_per_key = count_versions_per_key(&versions);
+    let mut worker_pool = WorkerPool::new(client_bucket.args.concurrency);
+    for (key, version_id) in &versions {
+        let append_version_id = versions_per_key[key.as_str()] > 1;
+        worker_pool.submit(download_version(client_bucket, key, version_id, append_version_id, output_printer)).await;
+    }
+    while worker_pool.next().await.is_some() {}
+    Ok(())
+}
+
+async fn download_version(client_bucket: &ClientBucket,
+                          key: &str,
+                          version_id: &str,
+                          append_version_id: bool,
+                          output_printer: &dyn OutputPrinter) {
+    let args = &client_bucket.args;
+    let target_folder = args.target_folder.as_ref().expect("Please define the target folder for download");
+    let new_path = match key_to_path(Path::new(target_folder), key, args.flatten, args.strip_prefix.as_deref()) {
+        Some(path) if append_version_id => versioned_path(&path, version_id),
+        Some(path) => path,
+        None => {
+            output_printer.err_output(format!("Cannot map {} to a local path", key).as_str());
+            return;
+        }
+    };
+    let head = client_bucket.client
+        .head_object()
+        .bucket(&client_bucket.bucket_name)
+        .key(key)
+        .version_id(version_id)
+        .send()
+        .await;
+    let obj = match head {
+        Ok(head) => Object::builder()
+            .key(key)
+            .size(head.content_length())
+            .set_e_tag(head.e_tag().map(|e| e.to_string()))
+            .set_last_modified(head.last_modified().cloned())
+            .build(),
+        Err(e) => {
+            output_printer.err_output(format!("Cannot download {} version {} due to {:?}", key, version_id, e).as_str());
+            return;
+        }
+    };
+    let if_exists = args.if_exists.unwrap_or(ExistsPolicy::Overwrite);
+    if !should_transfer(if_exists, &FileStats::from_object(&obj), local_stats(&new_path).as_ref()) {
+        output_printer.ok_output(format!("Skipped {} version {} as {:?} already exists", key, version_id, new_path).as_str());
+        return;
+    }
+    download_object_to(client_bucket, &obj, Some(version_id), &new_path, output_printer).await;
+}
+
+fn count_versions_per_key(versions: &[(String, String)]) -> HashMap<&str, usize> {
+    let mut versions_per_key: HashMap<&str, usize> = HashMap::new();
+    for (key, _) in versions {
+        *versions_per_key.entry(key.as_str()).or_default() += 1;
+    }
+    versions_per_key
+}
+
+fn versioned_path(path: &Path, version_id: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", version_id));
+    PathBuf::from(name)
+}
+
+/// Copies the versions given by `--versions-file` into the folder `--target-key`, like copy-multiple.
+/// When a key is selected with several versions, the version id is appended to the key of each copy.
+/// Moving permanently deletes the versions which were copied.
+pub(crate) async fn copy_versions(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter, remove_source: bool) -> Result<(), String> {
+    let args = &client_bucket.args;
+    let target_folder = args.target_key.as_ref().ok_or("Target key is missing. Please specify the target key")?;
+    let versions = selected_versions(args)?;
+    let versions_per_key = count_versions_per_key(&versions);
+    let mut copied: Vec<(String, Option<String>)> = Vec::new();
+    let mut worker_pool = WorkerPool::new(args.concurrency);
+    for (key, version_id) in &versions {
+        let target_key = versioned_target_key(target_folder, key, version_id, versions_per_key[key.as_str()] > 1);
+        copied.extend(worker_pool.submit(copy_version(client_bucket, key, version_id, target_key, output_printer)).await.flatten());
+    }
+    while let Some(version) = worker_pool.next().await {
+        copied.extend(version);
+    }
+    if remove_source {
+        delete_versions_in_batches(client_bucket, copied, true, output_printer).await;
+    }
+    Ok(())
+}
+
+async fn copy_version(client_bucket: &ClientBucket,
+                      key: &String,
+                      version_id: &str,
+                      target_key: String,
+                      output_printer: &dyn OutputPrinter) -> Option<(String, Option<String>)> {
+    match copy_from_key_to_target(client_bucket, key, Some(version_id), &target_key).await {
+        Ok(()) => {
+            output_printer.ok_output(format!("Copied {} version {} to {}", key, version_id, target_key).as_str());
+            Some((key.clone(), Some(version_id.to_string())))
+        }
+        Err(e) => {
+            output_printer.err_output(format!("Failed to copy {} version {} to {} due to {:?}", key, version_id, target_key, e).as_str());
+            None
+        }
+    }
+}
+
+fn versioned_target_key(target_folder: &str, key: &str, version_id: &str, append_version_id: bool) -> String {
+    let target_key = format!("{}/{}", target_folder, key.split('/').last().unwrap_or(key));
+    if append_version_id {
+        format!("{}.{}", target_key, version_id)
+    } else {
+        target_key
+    }
+}
+
+/// Permanently deletes the selected versions. Deleting a delete marker brings back the version below it.
+pub(crate) async fn delete_versions(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let versions: Vec<(String, Option<String>)> = selected_versions(&client_bucket.args)?.into_iter()
+        .map(|(key, version_id)| (key, Some(version_id)))
+        .collect();
+    delete_versions_in_batches(client_bucket, versions, true, output_printer).await;
+    Ok(())
+}
+
+/// Removes the delete markers which hide the keys below `--prefix` matching the list regex,
+/// so that their latest version becomes current again.
+pub(crate) async fn undelete(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let mut markers: Vec<(String, Option<String>)> = Vec::new();
+    let mut version_pages = VersionPages::new(client_bucket)?;
+    while let Some(page) = version_pages.next_page().await
+        .map_err(|e| format!("Could not list bucket versions: {:?}", e))? {
+        for version in page {
+            if version.is_delete_marker && version.is_latest {
+                output_printer.info_output(format!("Undelete {} by removing delete marker {}", version.key, version.version_id).as_str());
+                markers.push((version.key, Some(version.version_id)));
+            }
+        }
+    }
+    version_pages.print_stats(output_printer);
+    output_printer.info_output(format!("{} keys to undelete", markers.len()).as_str());
+    if !client_bucket.args.dry_run {
+        delete_versions_in_batches(client_bucket, markers, true, output_printer).await;
+    }
+    Ok(())
+}
+
+/// Reads `key,version id` pairs, or the lines printed by list-object-versions, so that its output
+/// can be used as it is. Keys may contain the separator, so the fields are split off from the right
+/// and nothing is trimmed. Other lines are ignored.
+fn parse_versions(content: &str, sep: &str) -> Vec<(String, String)> {
+    content.lines()
+        .filter_map(|line| {
+            let (key, version_id) = split_listed_version(line, sep)
+                .or_else(|| line.rsplit_once(sep))?;
+            if key.is_empty() || version_id.is_empty() || version_id == "<DIR>" {
+                return None;
+            }
+            Some((key.to_string(), version_id.to_string()))
+        })
+        .collect()
+}
+
+/// Splits a `key,version id,status,date,size` line of list-object-versions into its key and version id.
+fn split_listed_version<'l>(line: &'l str, sep: &str) -> Option<(&'l str, &'l str)> {
+    let fields: Vec<&str> = line.rsplitn(5, sep).collect();
+    match fields.as_slice() {
+        [_size, _date, status, version_id, key] if *status == "latest" || *status == "noncurrent" => Some((key, version_id)),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_versions() {
+        let listing = "Bucket: mdm-eu-prod-republish\n\
+                       data/,<DIR>\n\
+                       data/report.csv,3HL4kqtJlcpXroDTDmJ,noncurrent,2022-10-05T12:30:00,12 Kb\n\
+                       data/old.csv,null\n\
+                       data/a,b.csv,Fy8vZ2Lk,latest,2022-10-05T12:30:00,<DELETE MARKER>\n\
+                       data/c,d.csv,Qm3xW\n\
+                       \x20data/e.csv ,null\n\
+                       Scanned 5 versions in 1 pages\n";
+        assert_eq!(parse_versions(listing, ","), vec![
+            ("data/report.csv".to_string(), "3HL4kqtJlcpXroDTDmJ".to_string()),
+            ("data/old.csv".to_string(), "null".to_string()),
+            ("data/a,b.csv".to_string(), "Fy8vZ2Lk".to_string()),
+            ("data/c,d.csv".to_string(), "Qm3xW".to_string()),
+            (" data/e.csv ".to_string(), "null".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_versioned_target_key() {
+        assert_eq!(versioned_target_key("restore", "data/report.csv", "v1", false), "restore/report.csv");
+        assert_eq!(versioned_target_key("restore", "data/report.csv", "v1", true), "restore/report.csv.v1");
+    }
+
+    #[test]
+    fn test_versioned_path() {
+        let path = versioned_path(Path::new("restore").join("report.csv").as_path(), "v1");
+        assert_eq!(path, Path::new("restore").join("report.csv.v1"));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aws_client::{ClientBucket, OutputPrinter};
+use aws_client::file_delete::{delete_versions_batch, MAX_DELETE_BATCH};
+use aws_client::object_versions::{VersionPages, VersionRecord};
+
+use crate::upload_files::MIB;
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// Permanently deletes the noncurrent versions of the keys below `--prefix` matching the list regex
+/// which are beyond the newest `--keep-versions` noncurrent versions of their key and which became
+/// noncurrent more than `--older-than-days` days ago. Either limit can be left out.
+pub(crate) async fn prune_versions(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let args = &client_bucket.args;
+    if args.keep_versions.is_none() && args.older_than_days.is_none() {
+        return Err("Please specify --keep-versions or --older-than-days".to_string());
+    }
+    let noncurrent_before = args.older_than_days.map(|days| now_secs() - days as i64 * SECONDS_PER_DAY);
+    let mut pruning = Pruning {
+        client_bucket,
+        output_printer,
+        batch: Vec::new(),
+        sizes: HashMap::new(),
+        selected: 0,
+        deleted: 0,
+        reclaimed: 0,
+    };
+    // Versions come ordered by key, so each key is complete once the next one starts
+    let mut key_versions: Vec<VersionRecord> = Vec::new();
+    let mut version_pages = VersionPages::new(client_bucket)?;
+    while let Some(page) = version_pages.next_page().await
+        .map_err(|e| format!("Could not list bucket versions: {:?}", e))? {
+        for version in page {
+            if matches!(key_versions.first(), Some(first) if first.key != version.key) {
+                let prunable = select_prunable(&key_versions, args.keep_versions, noncurrent_before);
+                pruning.add(prunable).await;
+                key_versions.clear();
+            }
+            key_versions.push(version);
+        }
+    }
+    let prunable = select_prunable(&key_versions, args.keep_versions, noncurrent_before);
+    pruning.add(prunable).await;
+    pruning.flush().await;
+    version_pages.print_stats(output_printer);
+
+    if args.dry_run {
+        output_printer.info_output(format!("{} noncurrent versions to delete, reclaiming {:.1} MiB",
+                                           pruning.selected, pruning.reclaimed as f64 / MIB as f64).as_str());
+    } else {
+        output_printer.info_output(format!("Deleted {} of {} noncurrent versions, reclaimed {:.1} MiB",
+                                           pruning.deleted, pruning.selected, pruning.reclaimed as f64 / MIB as f64).as_str());
+    }
+    Ok(())
+}
+
+/// Collects the versions to delete into batches, adding up the size of the deleted versions.
+struct Pruning<'a> {
+    client_bucket: &'a ClientBucket,
+    output_printer: &'a dyn OutputPrinter,
+    batch: Vec<(String, Option<String>)>,
+    sizes: HashMap<(String, String), i64>,
+    selected: usize,
+    deleted: usize,
+    reclaimed: i64,
+}
+
+impl<'a> Pruning<'a> {
+    async fn add(&mut self, versions: Vec<&VersionRecord>) {
+        for version in versions {
+            self.selected += 1;
+            if self.client_bucket.args.dry_run {
+                self.output_printer.info_output(format!("Delete {} version {}", version.key, version.version_id).as_str());
+                self.reclaimed += version.size;
+                continue;
+            }
+            self.sizes.insert((version.key.clone(), version.version_id.clone()), version.size);
+            self.batch.push((version.key.clone(), Some(version.version_id.clone())));
+            if self.batch.len() == MAX_DELETE_BATCH {
+                self.flush().await;
+            }
+        }
+    }
+
+    async fn flush(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.batch);
+        for (key, version_id) in delete_versions_batch(self.client_bucket, batch, self.output_printer).await {
+            self.deleted += 1;
+            self.reclaimed += self.sizes.remove(&(key, version_id.unwrap_or_default())).unwrap_or_default();
+        }
+        self.sizes.clear();
+    }
+}
+
+/// The noncurrent versions of one key which are beyond the newest `keep_versions` noncurrent ones
+/// and became noncurrent before `noncurrent_before`. `versions` holds the versions and delete
+/// markers of the key, newest first. A version becomes noncurrent when the next one is created.
+/// Delete markers are not counted as kept versions, so they only go once they are older than those.
+fn select_prunable(versions: &[VersionRecord], keep_versions: Option<usize>, noncurrent_before: Option<i64>) -> Vec<&VersionRecord> {
+    let mut prunable = Vec::new();
+    let mut noncurrent_count = 0;
+    for (i, version) in versions.iter().enumerate() {
+        if version.is_latest || i == 0 {
+            continue;
+        }
+        let became_noncurrent = versions[i - 1].last_modified_secs();
+        let beyond_kept = !matches!(keep_versions, Some(keep) if noncurrent_count < keep);
+        let old_enough = !matches!(noncurrent_before, Some(before) if became_noncurrent >= before);
+        if beyond_kept && old_enough {
+            prunable.push(version);
+        }
+        if !version.is_delete_marker {
+            noncurrent_count += 1;
+        }
+    }
+    prunable
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const KEY: &str = "data/report.csv";
+
+    fn version_ids(versions: Vec<&VersionRecord>) -> Vec<&str> {
+        versions.iter().map(|v| v.version_id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_select_prunable() {
+        let versions = [VersionRecord::new(KEY, "v4", true, false, 400), VersionRecord::new(KEY, "v3", false, false, 300),
+                        VersionRecord::new(KEY, "v2", false, false, 200), VersionRecord::new(KEY, "v1", false, false, 100)];
+        assert_eq!(version_ids(select_prunable(&versions, Some(1), None)), vec!["v2", "v1"]);
+        assert_eq!(version_ids(select_prunable(&versions, None, Some(300))), vec!["v1"]);
+        assert_eq!(version_ids(select_prunable(&versions, Some(0), Some(350))), vec!["v2", "v1"]);
+        assert!(select_prunable(&versions, Some(3), None).is_empty());
+        assert!(select_prunable(&versions[..1], Some(0), None).is_empty());
+    }
+
+    #[test]
+    fn test_select_prunable_with_delete_marker() {
+        let versions = [VersionRecord::new(KEY, "v3", true, false, 300), VersionRecord::new(KEY, "m1", false, true, 200),
+                        VersionRecord::new(KEY, "v2", false, false, 200), VersionRecord::new(KEY, "v1", false, false, 100)];
+        assert_eq!(version_ids(select_prunable(&versions, Some(1), None)), vec!["v1"]);
+        assert_eq!(version_ids(select_prunable(&versions, Some(0), None)), vec!["m1", "v2", "v1"]);
+    }
+}
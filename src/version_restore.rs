@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, NaiveDateTime};
+
+use aws_client::{ClientBucket, OutputPrinter};
+use aws_client::file_delete::delete_versions_in_batches;
+use aws_client::object_versions::{VersionPages, VersionRecord};
+use aws_client::object_copy::copy_object_any_size;
+use aws_client::worker_pool::WorkerPool;
+
+use crate::copy_operations::handle_copy_error;
+
+#[derive(Debug, PartialEq)]
+enum RestoreAction {
+    CopyVersion(String),
+    AddDeleteMarker,
+}
+
+/// Puts every key below `--prefix` matching the list regex back into the state it had at
+/// `--timestamp`: the version current at that time is copied over the current version, and keys
+/// which did not exist or were deleted at that time get a delete marker. With `--dry-run` the
+/// planned changes are only printed.
+pub(crate) async fn restore_to_time(client_bucket: &ClientBucket, output_printer: &dyn OutputPrinter) -> Result<(), String> {
+    let args = &client_bucket.args;
+    let time = parse_timestamp(args.timestamp.as_ref().ok_or("Please specify the timestamp to restore to")?)?;
+    let mut versions_by_key: BTreeMap<String, Vec<VersionRecord>> = BTreeMap::new();
+    let mut version_pages = VersionPages::new(client_bucket)?;
+    while let Some(page) = version_pages.next_page().await
+        .map_err(|e| format!("Could not list bucket versions: {:?}", e))? {
+        for version in page {
+            versions_by_key.entry(version.key.clone()).or_default().push(version);
+        }
+    }
+    version_pages.print_stats(output_printer);
+
+    let mut copies: Vec<(String, String)> = Vec::new();
+    let mut delete_markers: Vec<(String, Option<String>)> = Vec::new();
+    for (key, versions) in versions_by_key {
+        match plan_restore(&versions, time) {
+            Some(RestoreAction::CopyVersion(version_id)) => {
+                output_printer.info_output(format!("Restore {} to version {}", key, version_id).as_str());
+                copies.push((key, version_id));
+            }
+            Some(RestoreAction::AddDeleteMarker) => {
+                output_printer.info_output(format!("Restore {} by adding a delete marker", key).as_str());
+                delete_markers.push((key, None));
+            }
+            None => {}
+        }
+    }
+    output_printer.info_output(format!("{} keys to restore from an older version, {} keys to delete",
+                                       copies.len(), delete_markers.len()).as_str());
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let mut worker_pool = WorkerPool::new(args.concurrency);
+    for (key, version_id) in &copies {
+        worker_pool.submit(restore_version(client_bucket, key, version_id, output_printer)).await;
+    }
+    while worker_pool.next().await.is_some() {}
+    delete_versions_in_batches(client_bucket, delete_markers, true, output_printer).await;
+    Ok(())
+}
+
+async fn restore_version(client_bucket: &ClientBucket, key: &str, version_id: &str, output_printer: &dyn OutputPrinter) {
+    let bucket_name = &client_bucket.bucket_name;
+    match copy_object_any_size(client_bucket, bucket_name, key, Some(version_id), bucket_name, key).await {
+        Ok(()) => {
+            output_printer.ok_output(format!("Restored {} to version {}", key, version_id).as_str());
+        }
+        Err(e) => {
+            handle_copy_error(output_printer, format!("{} version {}", key, version_id), key.to_string(), e);
+        }
+    }
+}
+
+/// What brings a key back to its state at `time`, `None` if it is in that state already.
+/// `versions` holds the versions and delete markers of the key, newest first.
+fn plan_restore(versions: &[VersionRecord], time: i64) -> Option<RestoreAction> {
+    let current = versions.iter().find(|version| version.is_latest)?;
+    let at_time = versions.iter().find(|version| version.last_modified_secs() <= time);
+    match at_time {
+        Some(version) if !version.is_delete_marker => {
+            if version.version_id == current.version_id {
+                None
+            } else {
+                Some(RestoreAction::CopyVersion(version.version_id.clone()))
+            }
+        }
+        _ if current.is_delete_marker => None,
+        _ => Some(RestoreAction::AddDeleteMarker)
+    }
+}
+
+/// Accepts RFC 3339 timestamps like '2022-10-05T14:30:00+02:00' and UTC times like '2022-10-05 12:30:00'.
+fn parse_timestamp(timestamp: &str) -> Result<i64, String> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(timestamp) {
+        return Ok(date_time.timestamp());
+    }
+    ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"].iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(timestamp, format).ok())
+        .map(|date_time| date_time.timestamp())
+        .ok_or_else(|| format!("Invalid timestamp {}, expected e.g. 2022-10-05T12:30:00Z", timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const KEY: &str = "data/report.csv";
+
+    #[test]
+    fn test_plan_restore() {
+        let overwritten = [VersionRecord::new(KEY, "v3", true, false, 300), VersionRecord::new(KEY, "v2", false, false, 200),
+                           VersionRecord::new(KEY, "v1", false, false, 100)];
+        assert_eq!(plan_restore(&overwritten, 250), Some(RestoreAction::CopyVersion("v2".to_string())));
+        assert_eq!(plan_restore(&overwritten, 300), None);
+        assert_eq!(plan_restore(&overwritten, 50), Some(RestoreAction::AddDeleteMarker));
+
+        let deleted = [VersionRecord::new(KEY, "m1", true, true, 300), VersionRecord::new(KEY, "v1", false, false, 100)];
+        assert_eq!(plan_restore(&deleted, 200), Some(RestoreAction::CopyVersion("v1".to_string())));
+        assert_eq!(plan_restore(&deleted, 50), None);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("2022-10-05T12:30:00Z"), Ok(1664973000));
+        assert_eq!(parse_timestamp("2022-10-05T14:30:00+02:00"), Ok(1664973000));
+        assert_eq!(parse_timestamp("2022-10-05 12:30:00"), Ok(1664973000));
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+}
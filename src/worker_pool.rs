@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+use std::future::Future;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+
+pub const DEFAULT_CONCURRENCY: usize = 1;
+
+/// Runs futures with at most `concurrency` of them in flight at any time.
+/// The futures only make progress while the pool is awaited, so a caller which waits for
+/// something else in between, like the next page of a listing, does so through `run_alongside`.
+pub struct WorkerPool<Fut: Future> {
+    in_flight: FuturesUnordered<Fut>,
+    finished: VecDeque<Fut::Output>,
+    concurrency: usize,
+}
+
+impl<Fut: Future> WorkerPool<Fut> {
+    pub fn new(concurrency: Option<usize>) -> WorkerPool<Fut> {
+        WorkerPool {
+            in_flight: FuturesUnordered::new(),
+            finished: VecDeque::new(),
+            concurrency: concurrency.unwrap_or(DEFAULT_CONCURRENCY).max(1),
+        }
+    }
+
+    /// Queues a future. When the pool is full this first waits for a running future to finish.
+    /// Returns the output of a finished future, if there is one.
+    pub async fn submit(&mut self, fut: Fut) -> Option<Fut::Output> {
+        if self.in_flight.len() >= self.concurrency {
+            if let Some(output) = self.in_flight.next().await {
+                self.finished.push_back(output);
+            }
+        }
+        self.in_flight.push(fut);
+        self.finished.pop_front()
+    }
+
+    /// Waits for the next running future to finish. Returns `None` once the pool is empty.
+    pub async fn next(&mut self) -> Option<Fut::Output> {
+        match self.finished.pop_front() {
+            Some(output) => Some(output),
+            None => self.in_flight.next().await
+        }
+    }
+
+    /// Awaits `fut` while the queued futures keep running. Their outputs are handed out
+    /// by the following calls of `submit` and `next`.
+    pub async fn run_alongside<T>(&mut self, fut: impl Future<Output=T>) -> T {
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                output = &mut fut => return output,
+                Some(output) = self.in_flight.next(), if !self.in_flight.is_empty() => {
+                    self.finished.push_back(output);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_alongside_drives_queued_futures() {
+        let (sender, receiver) = oneshot::channel();
+        let mut worker_pool = WorkerPool::new(Some(2));
+        assert_eq!(worker_pool.submit(async move { sender.send(7).is_ok() }).await, None);
+        // Only resolves if the queued future runs while it is awaited
+        assert_eq!(worker_pool.run_alongside(receiver).await, Ok(7));
+        assert_eq!(worker_pool.next().await, Some(true));
+        assert_eq!(worker_pool.next().await, None);
+    }
+}
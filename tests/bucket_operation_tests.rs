@@ -47,6 +47,8 @@ mod tests {
             region: "eu-west-2".to_string(),
             glob_pattern: Some("*".to_string()),
             list_regex_pattern: Some(".+".to_string()),
+            prefix: None,
+            delimiter: None,
             bucket: Some("gil.rust.test".to_string()),
             target_bucket: None,
             target_folder: None,
@@ -54,9 +56,33 @@ mod tests {
             sep: Some(",".to_string()),
             asc: None,
             source_key: None,
+            version_id: None,
+            versions_file: None,
             target_key: None,
+            rename_template: None,
+            metadata_directive: None,
+            metadata: vec![],
+            tagging_directive: None,
+            tag: vec![],
+            storage_class: None,
+            acl: None,
             flatten: false,
+            if_exists: None,
+            strip_prefix: None,
+            local_folder: None,
+            sync_direction: None,
+            delete: false,
+            checksum: false,
+            timestamp: None,
+            keep_versions: None,
+            older_than_days: None,
+            dry_run: false,
+            force: false,
             strict_bucket: false,
+            chunk_size: None,
+            multipart_threshold: None,
+            concurrency: None,
+            upload_file: None,
         };
     }
 